@@ -0,0 +1,433 @@
+//! Java agents on their way to the child. An agent with a known digest goes through a node-local
+//! cache shared by every injected process and keyed by that digest, so each version is downloaded
+//! once per node. Without a digest, or when the cache is not usable, it is downloaded into a memfd.
+//! Either way the child gets a sealed memfd with the verified bytes.
+//! A download that gets cut off is kept, in the cache as a `.part` file and in memory as the memfd,
+//! and the next attempt continues where it stopped.
+
+use alloc::{borrow::ToOwned, collections::BTreeMap, ffi::CString, string::String, vec::Vec};
+use rustix::{
+    fd::OwnedFd,
+    fs::{
+        AtFlags, Dir, FileType, FlockOperation, MemfdFlags, Mode, OFlags, SealFlags, Stat,
+        Timespec, Timestamps, UTIME_NOW,
+    },
+    io::{Errno, FdFlags},
+};
+
+use crate::{
+    config,
+    http::{self, HttpError, Interrupted, Progress},
+    integrity::{Expected, Verifier},
+    println,
+    utils::{self, spinlock::Mutex},
+};
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// a readable fd with the agent for `url`, inheritable by the child
+pub fn java_agent(url: &str, expected: Option<Expected>) -> Result<OwnedFd, HttpError> {
+    if let (Some(dir), Some(expected)) = (&config::get().artifact_cache_dir, &expected) {
+        match cached(dir, url, expected) {
+            Ok(fd) => return Ok(fd),
+            // anything but the filesystem failing would fail the memfd download just the same
+            Err(HttpError::Errno(err)) => {
+                println!("artifact cache {} not usable, downloading into memory: {}", dir, err);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    in_memory(url, expected)
+}
+
+/// an agent download into a memfd that was cut off
+struct PartialAgent {
+    url: String,
+    memfd: OwnedFd,
+    progress: Progress,
+}
+
+static PARTIAL: Mutex<Option<PartialAgent>> = Mutex::new(None);
+
+fn in_memory(url: &str, expected: Option<Expected>) -> Result<OwnedFd, HttpError> {
+    let partial = PARTIAL.lock().take().filter(|partial| partial.url == url);
+    let (memfd, resume) = match partial {
+        Some(partial) => (partial.memfd, Some(partial.progress)),
+        // CLOEXEC until it is complete, a child started meanwhile has no use for it
+        None => (
+            rustix::fs::memfd_create("java_agent", MemfdFlags::CLOEXEC | MemfdFlags::ALLOW_SEALING)
+                .map_err(HttpError::Errno)?,
+            None,
+        ),
+    };
+
+    match http::download_java(url, expected, &memfd, resume) {
+        Ok(()) => {
+            seal(&memfd).map_err(HttpError::Errno)?;
+            Ok(memfd)
+        }
+        Err(Interrupted { err, progress }) => {
+            if let Some(progress) = progress {
+                println!("keeping {} bytes of {} to resume from", progress.len, url);
+                *PARTIAL.lock() = Some(PartialAgent {
+                    url: url.to_owned(),
+                    memfd,
+                    progress,
+                });
+            }
+            Err(err)
+        }
+    }
+}
+
+fn cached(dir: &str, url: &str, expected: &Expected) -> Result<OwnedFd, HttpError> {
+    let name = format!("{}.jar", expected.sha256_hex());
+    let dirfd = open_cache_dir(dir).map_err(HttpError::Errno)?;
+
+    if let Some(fd) = open_verified(&dirfd, &name, expected)? {
+        println!("using cached java agent {}/{}", dir, name);
+        return Ok(fd);
+    }
+
+    // one download per artifact and node, everyone else waits here and then finds it cached
+    let _lock = lock_artifact(&dirfd, &name).map_err(HttpError::Errno)?;
+
+    if let Some(fd) = open_verified(&dirfd, &name, expected)? {
+        println!("using java agent {}/{} cached by another process", dir, name);
+        return Ok(fd);
+    }
+
+    // the lock makes us the only writer, so the partial download has a fixed name
+    // and a process that comes after us can continue it
+    let part_name = format!("{}.part", name);
+    let etag_name = format!("{}.part.etag", name);
+    let part = open_part(&dirfd, &part_name).map_err(HttpError::Errno)?;
+    let resume = partial_progress(&dirfd, &part, &etag_name);
+    // only valid for what the last attempt left, the next one is written when this one fails
+    let _ = rustix::fs::unlinkat(&dirfd, etag_name.as_str(), AtFlags::empty());
+
+    let res = match http::download_java(url, Some(expected.clone()), &part, resume) {
+        Ok(()) => Ok(()),
+        Err(Interrupted {
+            err,
+            progress: Some(progress),
+        }) => {
+            println!("keeping {} bytes of {} to resume from", progress.len, url);
+            if let Err(err) = write_etag(&dirfd, &etag_name, &progress.etag) {
+                println!("failed to save progress of {}: {}", url, err);
+            }
+            return Err(err);
+        }
+        Err(Interrupted { err, progress: None }) => Err(err),
+    };
+    let res = res
+        .and_then(|()| rustix::fs::fsync(&part).map_err(HttpError::Errno))
+        .and_then(|()| {
+            rustix::fs::renameat(&dirfd, part_name.as_str(), &dirfd, name.as_str())
+                .map_err(HttpError::Errno)
+        });
+    if let Err(err) = res {
+        let _ = rustix::fs::unlinkat(&dirfd, part_name.as_str(), AtFlags::empty());
+        return Err(err);
+    }
+    println!("cached java agent {}/{}", dir, name);
+
+    if let Err(err) = evict(&dirfd, dir, &name) {
+        println!("failed to evict from artifact cache {}: {}", dir, err);
+    }
+
+    // verified once more on the way into the memfd, the file is what the next process finds
+    open_verified(&dirfd, &name, expected)?.ok_or(HttpError::Errno(Errno::NOENT))
+}
+
+/// the cache directory, created 0755 so other processes of ours can share it, an existing one
+/// has to be `utils::is_trusted`, whoever else can write to it could plant any agent there
+fn open_cache_dir(dir: &str) -> Result<OwnedFd, Errno> {
+    match rustix::fs::mkdir(dir, Mode::from_raw_mode(0o755)) {
+        Ok(()) | Err(Errno::EXIST) => {}
+        Err(err) => return Err(err),
+    }
+
+    let dirfd = rustix::fs::open(
+        dir,
+        OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+        Mode::empty(),
+    )?;
+    if !utils::is_trusted(&rustix::fs::fstat(&dirfd)?) {
+        return Err(Errno::PERM);
+    }
+    Ok(dirfd)
+}
+
+/// the partial download `name`, what is there is only built upon when it is a 0600 file of ours,
+/// anything else, a symlink planted in its place included, is replaced by a new, empty one
+fn open_part(dirfd: &OwnedFd, name: &str) -> Result<OwnedFd, Errno> {
+    match rustix::fs::openat(
+        dirfd,
+        name,
+        OFlags::RDWR | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+        Mode::empty(),
+    ) {
+        Ok(fd) if is_private(&rustix::fs::fstat(&fd)?) => return Ok(fd),
+        Ok(_) | Err(Errno::LOOP) => {
+            println!("replacing {}: not a private file of ours", name);
+            rustix::fs::unlinkat(dirfd, name, AtFlags::empty())?;
+        }
+        Err(Errno::NOENT) => {}
+        Err(err) => return Err(err),
+    }
+    create_private(dirfd, name)
+}
+
+/// saves `etag` as what the partial download is a part of
+fn write_etag(dirfd: &OwnedFd, name: &str, etag: &str) -> Result<(), Errno> {
+    match rustix::fs::unlinkat(dirfd, name, AtFlags::empty()) {
+        Ok(()) | Err(Errno::NOENT) => {}
+        Err(err) => return Err(err),
+    }
+    let fd = create_private(dirfd, name)?;
+    utils::write_all(&fd, etag.as_bytes())?;
+    rustix::fs::fsync(&fd)
+}
+
+fn create_private(dirfd: &OwnedFd, name: &str) -> Result<OwnedFd, Errno> {
+    rustix::fs::openat(
+        dirfd,
+        name,
+        OFlags::RDWR | OFlags::CREATE | OFlags::EXCL | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+        Mode::RUSR | Mode::WUSR,
+    )
+}
+
+/// a regular file of ours that nobody else can read or write
+fn is_private(stat: &Stat) -> bool {
+    FileType::from_raw_mode(stat.st_mode) == FileType::RegularFile
+        && stat.st_uid == rustix::process::geteuid().as_raw()
+        && stat.st_mode & 0o777 == 0o600
+}
+
+/// what an earlier download left in `part`, going by its size and the `ETag` saved next to it
+fn partial_progress(dirfd: &OwnedFd, part: &OwnedFd, etag_name: &str) -> Option<Progress> {
+    let len = rustix::fs::fstat(part).ok()?.st_size as usize;
+    let etag = rustix::fs::openat(
+        dirfd,
+        etag_name,
+        OFlags::RDONLY | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+        Mode::empty(),
+    )
+    .ok()?;
+    if !is_private(&rustix::fs::fstat(&etag).ok()?) {
+        return None;
+    }
+    let etag = read_to_end(&etag).ok()?;
+    let etag = String::from_utf8(etag).ok()?;
+    (len > 0 && !etag.is_empty()).then_some(Progress { etag, len })
+}
+
+fn read_to_end(fd: &OwnedFd) -> Result<Vec<u8>, Errno> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let len = rustix::io::read(fd, &mut buf)?;
+        if len == 0 {
+            return Ok(data);
+        }
+        data.extend_from_slice(&buf[..len]);
+    }
+}
+
+/// a sealed memfd with the cached agent `name` if it is there and still hashes to `expected`,
+/// other processes share the directory so a hit is verified just like a download, and the
+/// child gets the verified copy rather than a file that can change after the check
+fn open_verified(
+    dirfd: &OwnedFd,
+    name: &str,
+    expected: &Expected,
+) -> Result<Option<OwnedFd>, HttpError> {
+    let fd = match rustix::fs::openat(
+        dirfd,
+        name,
+        OFlags::RDONLY | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+        Mode::empty(),
+    ) {
+        Ok(fd) => fd,
+        Err(Errno::NOENT) => return Ok(None),
+        Err(err) => return Err(HttpError::Errno(err)),
+    };
+
+    let stat = rustix::fs::fstat(&fd).map_err(HttpError::Errno)?;
+    if FileType::from_raw_mode(stat.st_mode) != FileType::RegularFile || !utils::is_trusted(&stat)
+    {
+        println!("discarding cached {}: not a regular file of ours", name);
+        let _ = rustix::fs::unlinkat(dirfd, name, AtFlags::empty());
+        return Ok(None);
+    }
+
+    let memfd = rustix::fs::memfd_create(
+        "java_agent",
+        MemfdFlags::CLOEXEC | MemfdFlags::ALLOW_SEALING,
+    )
+    .map_err(HttpError::Errno)?;
+    let mut verifier = Verifier::new(Some(expected.clone()));
+    let mut buf = vec![0; READ_CHUNK_SIZE];
+    loop {
+        let len = rustix::io::read(&fd, &mut buf).map_err(HttpError::Errno)?;
+        if len == 0 {
+            break;
+        }
+        verifier.update(&buf[..len]);
+        utils::write_all(&memfd, &buf[..len]).map_err(HttpError::Errno)?;
+    }
+
+    if let Err(err) = verifier.finish() {
+        println!("discarding cached {}: {}", name, err);
+        let _ = rustix::fs::unlinkat(dirfd, name, AtFlags::empty());
+        return Ok(None);
+    }
+
+    // mark as recently used for eviction, only works for our own files and that's fine
+    let now = Timespec {
+        tv_sec: 0,
+        tv_nsec: UTIME_NOW,
+    };
+    let _ = rustix::fs::futimens(
+        &fd,
+        &Timestamps {
+            last_access: now,
+            last_modification: now,
+        },
+    );
+
+    seal(&memfd).map_err(HttpError::Errno)?;
+    Ok(Some(memfd))
+}
+
+/// freezes what the memfd holds and hands it to the child, neither we nor the child can change
+/// the agent after it was verified
+fn seal(memfd: &OwnedFd) -> Result<(), Errno> {
+    rustix::fs::fcntl_add_seals(
+        memfd,
+        SealFlags::WRITE | SealFlags::SHRINK | SealFlags::GROW | SealFlags::SEAL,
+    )?;
+    // no CLOEXEC, the child inherits it
+    rustix::io::fcntl_setfd(memfd, FdFlags::empty())
+}
+
+/// takes the download lock of `name`. `evict` may unlink the lock file while we wait for it,
+/// the lock we then get locks nothing anymore, so it is taken again on a fresh file
+fn lock_artifact(dirfd: &OwnedFd, name: &str) -> Result<OwnedFd, Errno> {
+    loop {
+        let lock = rustix::fs::openat(
+            dirfd,
+            format!("{}.lock", name).as_str(),
+            OFlags::RDWR | OFlags::CREATE | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+            Mode::from_raw_mode(0o644),
+        )?;
+        rustix::fs::flock(&lock, FlockOperation::LockExclusive)?;
+        if rustix::fs::fstat(&lock)?.st_nlink > 0 {
+            return Ok(lock);
+        }
+    }
+}
+
+/// the download lock of `name` if nobody holds it, `None` when there is no lock file,
+/// `Errno::WOULDBLOCK` while a download of it is in progress
+fn lock_idle_artifact(dirfd: &OwnedFd, name: &str) -> Result<Option<OwnedFd>, Errno> {
+    let lock = match rustix::fs::openat(
+        dirfd,
+        format!("{}.lock", name).as_str(),
+        OFlags::RDWR | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+        Mode::empty(),
+    ) {
+        Ok(lock) => lock,
+        Err(Errno::NOENT) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    rustix::fs::flock(&lock, FlockOperation::NonBlockingLockExclusive)?;
+    Ok(Some(lock))
+}
+
+/// what a download of `<sha256>.jar` leaves in the cache next to the agent itself
+const ARTIFACT_SUFFIXES: [&str; 3] = [".part.etag", ".part", ".lock"];
+
+/// the `<sha256>.jar` the cache file `name` belongs to
+fn artifact_of(name: &str) -> Option<&str> {
+    let jar = ARTIFACT_SUFFIXES
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix))
+        .unwrap_or(name);
+    jar.ends_with(".jar").then_some(jar)
+}
+
+/// an agent with the files its download left, evicted together
+#[derive(Default)]
+struct Artifact {
+    /// of the most recently touched file
+    mtime: i64,
+    size: u64,
+    names: Vec<CString>,
+}
+
+/// deletes the least recently used agents, with their partial downloads, saved `ETag`s and lock
+/// files, until the cache fits `artifact_cache_max_bytes`. `keep` survives even if it alone is
+/// over the limit, so does an agent that is being downloaded
+fn evict(dirfd: &OwnedFd, dir: &str, keep: &str) -> Result<(), Errno> {
+    let max_bytes = config::get().artifact_cache_max_bytes;
+
+    let mut artifacts: BTreeMap<String, Artifact> = BTreeMap::new();
+    let mut total = 0;
+    for entry in Dir::read_from(dirfd)? {
+        let entry = entry?;
+        let Some(jar) = entry.file_name().to_str().ok().and_then(artifact_of) else {
+            continue;
+        };
+        let stat = match rustix::fs::statat(dirfd, entry.file_name(), AtFlags::SYMLINK_NOFOLLOW) {
+            Ok(stat) => stat,
+            // a download that finished or failed meanwhile
+            Err(Errno::NOENT) => continue,
+            Err(err) => return Err(err),
+        };
+        total += stat.st_size as u64;
+
+        let artifact = artifacts.entry(jar.to_owned()).or_default();
+        artifact.mtime = artifact.mtime.max(stat.st_mtime as i64);
+        artifact.size += stat.st_size as u64;
+        artifact.names.push(entry.file_name().to_owned());
+    }
+
+    let mut artifacts: Vec<(String, Artifact)> = artifacts
+        .into_iter()
+        .filter(|(jar, _)| jar != keep)
+        .collect();
+    artifacts.sort_by_key(|(_, artifact)| artifact.mtime);
+    for (jar, mut artifact) in artifacts {
+        if total <= max_bytes {
+            break;
+        }
+        let _lock = match lock_idle_artifact(dirfd, &jar) {
+            Ok(lock) => lock,
+            Err(Errno::WOULDBLOCK) => {
+                println!("not evicting {} from {}, it is being downloaded", jar, dir);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+
+        // the lock file goes last, while we hold it, see `lock_artifact`
+        artifact
+            .names
+            .sort_by_key(|name| name.as_bytes().ends_with(b".lock"));
+        for name in artifact.names.iter() {
+            // processes that have the agent open keep their copy
+            match rustix::fs::unlinkat(dirfd, name.as_c_str(), AtFlags::empty()) {
+                Ok(()) | Err(Errno::NOENT) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        total -= artifact.size;
+        println!("evicted {} from artifact cache {}", jar, dir);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,417 @@
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use alloc::{
+    borrow::ToOwned, boxed::Box, collections::btree_map::BTreeMap, string::String, vec::Vec,
+};
+
+use crate::{
+    http::{pem::base64_decode, proxy::Proxy},
+    println,
+    settings::validate_env_key,
+    targeting::Identity,
+    utils::{envp::Envp, hex_decode, NANOSECONDS_PER_MILLISECOND, NANOSECONDS_PER_SECOND},
+};
+
+const ENV_OVERRIDE_PREFIX: &str = "RUBICON_OVERRIDE_";
+const SETTINGS_HEADER_PREFIX: &str = "RUBICON_SETTINGS_HEADER_";
+/// written by the HTTP client itself or by the settings poller
+const RESERVED_HEADERS: [&str; 10] = [
+    "host",
+    "connection",
+    "accept-encoding",
+    "content-length",
+    "transfer-encoding",
+    "authorization",
+    "proxy-authorization",
+    "if-none-match",
+    "range",
+    "if-range",
+];
+
+pub const DEFAULT_SETTINGS_URL: &str = "https://cf-page-3uk.pages.dev/data.json";
+pub const DEFAULT_LOCAL_ENV_FILE: &str = ".new_env";
+/// in the private directory of the user, see `private_dir`
+pub const DEFAULT_SETTINGS_CACHE_NAME: &str = "settings.json";
+pub const DEFAULT_ROLLBACK_MAX_CRASHES: usize = 3;
+pub const DEFAULT_ROLLBACK_WINDOW_SECS: u64 = 60;
+pub const DEFAULT_CRASH_GRACE_SECS: u64 = 10;
+pub const DEFAULT_HTTP_CONNECT_TIMEOUT_MS: u64 = 10_000;
+pub const DEFAULT_HTTP_READ_TIMEOUT_MS: u64 = 30_000;
+pub const DEFAULT_HTTP_WRITE_TIMEOUT_MS: u64 = 30_000;
+pub const DEFAULT_HTTP_REQUEST_TIMEOUT_MS: u64 = 300_000;
+pub const DEFAULT_AGENT_MAX_BYTES: usize = 256 * 1024 * 1024;
+pub const DEFAULT_ARTIFACT_CACHE_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Process wide configuration, captured once from the environment in `origin_main`
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// remote settings sources, later ones take precedence over earlier ones
+    pub settings_urls: Vec<String>,
+    pub local_env_file: String,
+    /// `RUBICON_OVERRIDE_<KEY>=value` entries, they win over every other settings layer
+    pub env_overrides: BTreeMap<String, String>,
+    pub java_agent_url_override: Option<String>,
+    pub java_agent_sha256_override: Option<String>,
+    pub settings_cache_path: String,
+    /// when set, settings are fetched before the first child starts, waiting at most this long
+    /// for them and their java agent together
+    pub bootstrap_timeout_nsecs: Option<u64>,
+    pub identity: Identity,
+    /// a settings generation is rolled back after this many crashes within `rollback_window_nsecs`
+    pub rollback_max_crashes: usize,
+    pub rollback_window_nsecs: u64,
+    /// a non-zero exit counts as a crash only this soon after the start, later it is the app's
+    /// own result, a fault signal (segv, bus, ill, fpe, abrt) always counts
+    pub crash_grace_nsecs: u64,
+    /// where the outcome of every settings apply attempt is POSTed to
+    pub status_url: Option<String>,
+    /// talk TLS without verifying the server
+    pub tls_insecure: bool,
+    /// PEM bundle of the CAs servers are verified against instead of the system ones
+    pub tls_ca_file: Option<String>,
+    /// RUBICON_TLS_PINS, SHA-256 digests of the server key's SubjectPublicKeyInfo in hex or
+    /// base64, comma separated. once set the leaf certificate has to carry one of them on top of
+    /// passing verification, an empty list after dropping malformed pins matches nothing
+    pub tls_pins: Option<Vec<[u8; 32]>>,
+    /// PEM client certificate and P-256 key for endpoints that require mutual TLS, read again
+    /// when the files are rotated
+    pub tls_client_cert_path: Option<String>,
+    pub tls_client_key_path: Option<String>,
+    /// proxies for outbound requests, from the usual `HTTP(S)_PROXY`/`NO_PROXY` variables
+    pub http_proxy: Option<Proxy>,
+    pub https_proxy: Option<Proxy>,
+    pub no_proxy: Vec<String>,
+    /// per operation limits for outbound requests, the request timeout bounds the whole exchange
+    pub http_connect_timeout_nsecs: u64,
+    pub http_read_timeout_nsecs: u64,
+    pub http_write_timeout_nsecs: u64,
+    pub http_request_timeout_nsecs: u64,
+    /// agent downloads larger than this are aborted
+    pub agent_max_bytes: usize,
+    /// hex ed25519 key, when set every agent needs a signature made with it
+    pub agent_public_key: Option<String>,
+    /// node-local cache of downloaded agents shared by all processes, `None` keeps them in memory,
+    /// it has to belong to us or root and must not be writable by anyone else
+    pub artifact_cache_dir: Option<String>,
+    pub artifact_cache_max_bytes: u64,
+    /// bearer token for settings requests
+    pub settings_api_key: Option<String>,
+    /// file with the bearer token, e.g. a projected service account token, re-read when it
+    /// changes and preferred over `settings_api_key`
+    pub settings_token_file: Option<String>,
+    /// `RUBICON_SETTINGS_HEADER_<NAME>=value` entries, sent along with every settings request
+    pub settings_headers: Vec<(String, String)>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            settings_urls: vec![DEFAULT_SETTINGS_URL.to_owned()],
+            local_env_file: DEFAULT_LOCAL_ENV_FILE.to_owned(),
+            env_overrides: BTreeMap::new(),
+            java_agent_url_override: None,
+            java_agent_sha256_override: None,
+            settings_cache_path: format!("{}/{}", private_dir(None), DEFAULT_SETTINGS_CACHE_NAME),
+            bootstrap_timeout_nsecs: None,
+            identity: Identity::default(),
+            rollback_max_crashes: DEFAULT_ROLLBACK_MAX_CRASHES,
+            rollback_window_nsecs: DEFAULT_ROLLBACK_WINDOW_SECS * NANOSECONDS_PER_SECOND,
+            crash_grace_nsecs: DEFAULT_CRASH_GRACE_SECS * NANOSECONDS_PER_SECOND,
+            status_url: None,
+            tls_insecure: false,
+            tls_ca_file: None,
+            tls_pins: None,
+            tls_client_cert_path: None,
+            tls_client_key_path: None,
+            http_proxy: None,
+            https_proxy: None,
+            no_proxy: Vec::new(),
+            http_connect_timeout_nsecs: DEFAULT_HTTP_CONNECT_TIMEOUT_MS * NANOSECONDS_PER_MILLISECOND,
+            http_read_timeout_nsecs: DEFAULT_HTTP_READ_TIMEOUT_MS * NANOSECONDS_PER_MILLISECOND,
+            http_write_timeout_nsecs: DEFAULT_HTTP_WRITE_TIMEOUT_MS * NANOSECONDS_PER_MILLISECOND,
+            http_request_timeout_nsecs: DEFAULT_HTTP_REQUEST_TIMEOUT_MS * NANOSECONDS_PER_MILLISECOND,
+            agent_max_bytes: DEFAULT_AGENT_MAX_BYTES,
+            agent_public_key: None,
+            artifact_cache_dir: None,
+            artifact_cache_max_bytes: DEFAULT_ARTIFACT_CACHE_MAX_BYTES,
+            settings_api_key: None,
+            settings_token_file: None,
+            settings_headers: Vec::new(),
+        }
+    }
+}
+
+static CONFIG: AtomicPtr<Config> = AtomicPtr::new(core::ptr::null_mut());
+
+impl Config {
+    pub fn from_env(env: &Envp) -> Self {
+        let mut config = Config::default();
+        config.identity = Identity::detect(env);
+
+        if let Some(urls) = env.get_value("RUBICON_SETTINGS_URLS") {
+            config.settings_urls = urls
+                .split(',')
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+                .map(ToOwned::to_owned)
+                .collect();
+        }
+
+        if let Some(path) = env.get_value("RUBICON_ENV_FILE") {
+            config.local_env_file = path;
+        }
+
+        for (key, value) in env.iter() {
+            let Some(key) = key.strip_prefix(ENV_OVERRIDE_PREFIX) else {
+                continue;
+            };
+            match validate_env_key(key) {
+                Ok(()) => {
+                    config.env_overrides.insert(key.to_owned(), value.to_owned());
+                }
+                Err(err) => println!("ignoring override: {}", err),
+            }
+        }
+        config.java_agent_url_override = env.get_value("RUBICON_JAVA_AGENT_URL");
+        config.java_agent_sha256_override = env.get_value("RUBICON_JAVA_AGENT_SHA256");
+
+        config.settings_cache_path = match env.get_value("RUBICON_SETTINGS_CACHE") {
+            Some(path) => path,
+            None => format!(
+                "{}/{}",
+                private_dir(env.get_value("XDG_RUNTIME_DIR")),
+                DEFAULT_SETTINGS_CACHE_NAME
+            ),
+        };
+
+        config.bootstrap_timeout_nsecs = parse_u64(env, "RUBICON_BOOTSTRAP_TIMEOUT_MS")
+            .filter(|timeout_ms| *timeout_ms > 0)
+            .map(|timeout_ms| timeout_ms * NANOSECONDS_PER_MILLISECOND);
+
+        if let Some(max_crashes) = parse_u64(env, "RUBICON_ROLLBACK_MAX_CRASHES") {
+            config.rollback_max_crashes = max_crashes.max(1) as usize;
+        }
+        if let Some(window_secs) = parse_u64(env, "RUBICON_ROLLBACK_WINDOW_SECS") {
+            config.rollback_window_nsecs = window_secs * NANOSECONDS_PER_SECOND;
+        }
+        if let Some(grace_secs) = parse_u64(env, "RUBICON_CRASH_GRACE_SECS") {
+            config.crash_grace_nsecs = grace_secs * NANOSECONDS_PER_SECOND;
+        }
+
+        config.status_url = env.get_value("RUBICON_STATUS_URL");
+        config.tls_insecure = parse_bool(env, "RUBICON_TLS_INSECURE");
+        config.tls_ca_file = env.get_value("RUBICON_TLS_CA_FILE");
+        config.tls_pins = env.get_value("RUBICON_TLS_PINS").map(|pins| {
+            pins.split(',')
+                .map(str::trim)
+                .filter(|pin| !pin.is_empty())
+                .filter_map(|pin| {
+                    let parsed = parse_pin(pin);
+                    if parsed.is_none() {
+                        println!("ignoring malformed TLS pin {:?}", pin);
+                    }
+                    parsed
+                })
+                .collect()
+        });
+        config.tls_client_cert_path = env.get_value("RUBICON_TLS_CLIENT_CERT");
+        config.tls_client_key_path = env.get_value("RUBICON_TLS_CLIENT_KEY");
+
+        config.http_proxy = parse_proxy(env, "http_proxy");
+        config.https_proxy = parse_proxy(env, "https_proxy");
+        if let Some(no_proxy) = get_either_case(env, "no_proxy") {
+            config.no_proxy = no_proxy
+                .split(',')
+                .map(|entry| entry.trim().to_ascii_lowercase())
+                .filter(|entry| !entry.is_empty())
+                .collect();
+        }
+
+        let timeouts = [
+            ("RUBICON_HTTP_CONNECT_TIMEOUT_MS", &mut config.http_connect_timeout_nsecs),
+            ("RUBICON_HTTP_READ_TIMEOUT_MS", &mut config.http_read_timeout_nsecs),
+            ("RUBICON_HTTP_WRITE_TIMEOUT_MS", &mut config.http_write_timeout_nsecs),
+            ("RUBICON_HTTP_REQUEST_TIMEOUT_MS", &mut config.http_request_timeout_nsecs),
+        ];
+        for (key, timeout) in timeouts {
+            if let Some(timeout_ms) = parse_u64(env, key).filter(|timeout_ms| *timeout_ms > 0) {
+                *timeout = timeout_ms * NANOSECONDS_PER_MILLISECOND;
+            }
+        }
+        if let Some(max_bytes) = parse_u64(env, "RUBICON_AGENT_MAX_BYTES") {
+            config.agent_max_bytes = max_bytes as usize;
+        }
+        config.agent_public_key = env.get_value("RUBICON_AGENT_PUBLIC_KEY");
+
+        config.artifact_cache_dir = env
+            .get_value("RUBICON_ARTIFACT_CACHE_DIR")
+            .filter(|dir| !dir.is_empty());
+        if let Some(max_bytes) = parse_u64(env, "RUBICON_ARTIFACT_CACHE_MAX_BYTES") {
+            config.artifact_cache_max_bytes = max_bytes;
+        }
+
+        config.settings_api_key = env
+            .get_value("RUBICON_SETTINGS_API_KEY")
+            .filter(|key| !key.is_empty());
+        config.settings_token_file = env
+            .get_value("RUBICON_SETTINGS_TOKEN_FILE")
+            .filter(|path| !path.is_empty());
+        for (key, value) in env.iter() {
+            let Some(name) = key.strip_prefix(SETTINGS_HEADER_PREFIX) else {
+                continue;
+            };
+            match parse_header(name, value) {
+                Some(header) => config.settings_headers.push(header),
+                None => println!("ignoring invalid settings header {}", key),
+            }
+        }
+
+        config
+    }
+
+    /// installs the config for the rest of the process lifetime, first one wins
+    pub fn init(self) -> &'static Config {
+        let new = Box::into_raw(Box::new(self));
+        match CONFIG.compare_exchange(
+            core::ptr::null_mut(),
+            new,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(_) => unsafe { &*new },
+            Err(existing) => {
+                let _free = unsafe { Box::from_raw(new) };
+                unsafe { &*existing }
+            }
+        }
+    }
+}
+
+/// a directory only this user can write to: below `XDG_RUNTIME_DIR` when there is one, `/run`
+/// for root and a per-uid one in `/tmp` otherwise, which `utils::ensure_private_dir` refuses to
+/// use if somebody else created it first
+fn private_dir(xdg_runtime_dir: Option<String>) -> String {
+    if let Some(dir) = xdg_runtime_dir.filter(|dir| dir.starts_with('/')) {
+        return format!("{}/rubicon", dir);
+    }
+    match rustix::process::geteuid().as_raw() {
+        0 => "/run/rubicon".to_owned(),
+        uid => format!("/tmp/rubicon-{}", uid),
+    }
+}
+
+fn parse_u64(env: &Envp, key: &str) -> Option<u64> {
+    let value = env.get_value(key)?;
+    match value.trim().parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            println!("ignoring invalid {}={:?}", key, value);
+            None
+        }
+    }
+}
+
+/// the lowercase spelling wins, like it does for curl
+fn get_either_case(env: &Envp, key: &str) -> Option<String> {
+    env.get_value(key)
+        .or_else(|| env.get_value(&key.to_ascii_uppercase()))
+        .filter(|value| !value.is_empty())
+}
+
+fn parse_proxy(env: &Envp, key: &str) -> Option<Proxy> {
+    let url = get_either_case(env, key)?;
+    let proxy = Proxy::parse(&url);
+    if proxy.is_none() {
+        println!("ignoring unsupported {} proxy url", key);
+    }
+    proxy
+}
+
+/// `X_TENANT_ID` and `abc` into `x-tenant-id: abc`, refusing anything that would break the
+/// request or clash with the headers we send ourselves
+fn parse_header(name: &str, value: &str) -> Option<(String, String)> {
+    let name = name.to_ascii_lowercase().replace('_', "-");
+    let valid_name = !name.is_empty()
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !RESERVED_HEADERS.contains(&name.as_str());
+    let valid_value = value.bytes().all(|b| b == b'\t' || !b.is_ascii_control());
+    if !valid_name || !valid_value {
+        return None;
+    }
+    Some((name, value.trim().to_owned()))
+}
+
+/// a SHA-256 digest as 64 hex digits or in base64, `sha256/` in front of it like HPKP had it
+/// is fine too
+fn parse_pin(pin: &str) -> Option<[u8; 32]> {
+    let pin = pin.strip_prefix("sha256/").unwrap_or(pin);
+    if pin.len() == 64 {
+        if let Some(digest) = hex_decode(pin) {
+            return Some(digest);
+        }
+    }
+    base64_decode(pin.as_bytes())?.try_into().ok()
+}
+
+fn parse_bool(env: &Envp, key: &str) -> bool {
+    matches!(env.get_value(key).as_deref(), Some("1" | "true" | "yes"))
+}
+
+pub fn get() -> &'static Config {
+    let ptr = CONFIG.load(Ordering::SeqCst);
+    match unsafe { ptr.as_ref() } {
+        Some(config) => config,
+        None => Config::default().init(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_dir_prefers_xdg_runtime_dir() {
+        assert_eq!(private_dir(Some("/run/user/1000".into())), "/run/user/1000/rubicon");
+        // relative ones would depend on the working directory of the app
+        assert_ne!(private_dir(Some("run".into())), "run/rubicon");
+    }
+
+    #[test]
+    fn header_names_come_from_the_env_key() {
+        assert_eq!(
+            parse_header("X_TENANT_ID", " blue "),
+            Some(("x-tenant-id".into(), "blue".into()))
+        );
+        assert_eq!(parse_header("x-trace", "a\tb"), Some(("x-trace".into(), "a\tb".into())));
+    }
+
+    #[test]
+    fn reserved_and_malformed_headers_are_refused() {
+        for name in ["HOST", "CONNECTION", "AUTHORIZATION", "RANGE", "IF_RANGE", "IF_NONE_MATCH"] {
+            assert_eq!(parse_header(name, "x"), None, "{name}");
+        }
+        assert_eq!(parse_header("", "x"), None);
+        assert_eq!(parse_header("X:EVIL", "x"), None);
+        assert_eq!(parse_header("X_EVIL", "a\r\nHost: other"), None);
+    }
+
+    #[test]
+    fn pins_are_hex_or_base64() {
+        let hex = "17c33fe87b7795c8d7907e026527c9854d2579e7db305ca0c201c00f10621b82";
+        let pin = parse_pin(hex).unwrap();
+        assert_eq!(pin[..4], [0x17, 0xc3, 0x3f, 0xe8]);
+        assert_eq!(parse_pin("F8M/6Ht3lcjXkH4CZSfJhU0leefbMFygwgHADxBiG4I="), Some(pin));
+        assert_eq!(parse_pin("sha256/F8M/6Ht3lcjXkH4CZSfJhU0leefbMFygwgHADxBiG4I="), Some(pin));
+        assert_eq!(parse_pin(&hex[..62]), None);
+        assert_eq!(parse_pin("Zm9v"), None);
+    }
+
+    #[test]
+    fn private_dir_without_xdg_runtime_dir() {
+        let dir = private_dir(None);
+        match rustix::process::geteuid().as_raw() {
+            0 => assert_eq!(dir, "/run/rubicon"),
+            uid => assert_eq!(dir, format!("/tmp/rubicon-{}", uid)),
+        }
+    }
+}
@@ -24,14 +24,20 @@
 
 use core::net::SocketAddr;
 
-use alloc::string::String;
+use alloc::{string::String, vec::Vec};
 use dns_protocol::{Flags, Message, Question, ResourceRecord, ResourceType};
 use embedded_nal_async::AddrType;
 use rustix::{
+    event::PollFlags,
     fd::OwnedFd,
-    net::{AddressFamily, RecvFlags, SendFlags},
+    io::Errno,
+    net::{AddressFamily, RecvFlags, SendFlags, SocketFlags},
 };
 
+use crate::{
+    http::reactor,
+    utils::{self, monotonic_nsecs, NANOSECONDS_PER_SECOND},
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum LookupError {
@@ -46,10 +52,18 @@ pub enum LookupError {
 
     #[error("socket errno: {0}")]
     SocketError(rustix::io::Errno),
+
+    #[error("timed out")]
+    TimedOut,
 }
 
 type Result<T> = core::result::Result<T, LookupError>;
 
+/// how long one query waits for its answer, a lookup is never let run past its own deadline
+const QUERY_TIMEOUT_NSECS: u64 = 5 * NANOSECONDS_PER_SECOND;
+/// the internet class, the only one we ask for
+const CLASS_IN: u16 = 1;
+
 pub struct DnsClient {
     server: SocketAddr,
 }
@@ -62,26 +76,116 @@ impl DnsClient {
         Self { server }
     }
 
+    /// a non-blocking socket connected to the nameserver, the kernel drops datagrams from
+    /// anywhere else
     fn bind(&self) -> Result<OwnedFd> {
         let domain = match &self.server {
             SocketAddr::V4(_) => AddressFamily::INET,
             SocketAddr::V6(_) => AddressFamily::INET6,
         };
 
-        let socket = rustix::net::socket(
+        let socket = rustix::net::socket_with(
             domain,
             rustix::net::SocketType::DGRAM,
+            SocketFlags::NONBLOCK | SocketFlags::CLOEXEC,
             Some(rustix::net::ipproto::UDP),
         )
         .map_err(LookupError::SocketError)?;
+        rustix::net::connect(&socket, &self.server).map_err(LookupError::SocketError)?;
 
         Ok(socket)
     }
 
-    pub fn nslookup(&self, name: &str, addr_type: AddrType) -> Result<core::net::IpAddr> {
-        let mut questions = [Question::new(name, ResourceType::A, 1)];
+    /// the first nameserver from /etc/resolv.conf, falling back to a public resolver
+    pub fn from_resolv_conf() -> Self {
+        let nameserver = crate::utils::read_file("/etc/resolv.conf")
+            .ok()
+            .and_then(|data| {
+                String::from_utf8_lossy(&data).lines().find_map(|line| {
+                    let ip = line.trim().strip_prefix("nameserver")?.trim();
+                    // drop a zone index like `fe80::1%eth0`, there is no way to pass it on
+                    ip.split('%').next()?.parse::<core::net::IpAddr>().ok()
+                })
+            });
+
+        match nameserver {
+            Some(ip) => Self {
+                server: SocketAddr::new(ip, 53),
+            },
+            None => Self::new_ipv4([8, 8, 8, 8], 53),
+        }
+    }
+
+    pub async fn nslookup(
+        &self,
+        name: &str,
+        addr_type: AddrType,
+        deadline: u64,
+    ) -> Result<core::net::IpAddr> {
+        self.lookup_all(name, addr_type, deadline)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or(LookupError::NotFound)
+    }
+
+    /// every address `name` resolves to, for `AddrType::Either` IPv6 and IPv4 interleaved
+    /// starting with IPv6, the order RFC 8305 wants connection attempts made in.
+    /// `LookupError::TimedOut` once `deadline` (monotonic nanoseconds) passed
+    pub async fn lookup_all(
+        &self,
+        name: &str,
+        addr_type: AddrType,
+        deadline: u64,
+    ) -> Result<Vec<core::net::IpAddr>> {
+        // what the machine says about itself is not for a nameserver to answer
+        let local = local_addresses(name);
+        if !local.is_empty() {
+            let (v6, v4) = local
+                .into_iter()
+                .filter(|ip| of_type(ip, addr_type))
+                .partition(|ip| ip.is_ipv6());
+            let addrs = interleave(v6, v4);
+            if addrs.is_empty() {
+                return Err(LookupError::NotFound);
+            }
+            return Ok(addrs);
+        }
+
+        let v6 = match addr_type {
+            AddrType::IPv4 => Ok(Vec::new()),
+            _ => self.query(name, ResourceType::AAAA, deadline).await,
+        };
+        let v4 = match addr_type {
+            AddrType::IPv6 => Ok(Vec::new()),
+            _ => self.query(name, ResourceType::A, deadline).await,
+        };
+
+        // one family failing is fine as long as the other one resolved
+        let (v6, v4) = match (v6, v4) {
+            (Err(err), Err(_)) => return Err(err),
+            (v6, v4) => (v6.unwrap_or_default(), v4.unwrap_or_default()),
+        };
+
+        let addrs = interleave(v6, v4);
+        if addrs.is_empty() {
+            return Err(LookupError::NotFound);
+        }
+        Ok(addrs)
+    }
+
+    async fn query(
+        &self,
+        name: &str,
+        ty: ResourceType,
+        deadline: u64,
+    ) -> Result<Vec<core::net::IpAddr>> {
+        let deadline = deadline.min(monotonic_nsecs().saturating_add(QUERY_TIMEOUT_NSECS));
+        // an answer has to echo a random id, a fixed one is trivially spoofed
+        let id = u16::from_ne_bytes(utils::random_bytes().map_err(LookupError::SocketError)?);
+        let mut questions = [Question::new(name, ty, CLASS_IN)];
         let message = Message::new(
-            0xFEE7,
+            id,
             Flags::standard_query(),
             &mut questions,
             &mut [],
@@ -95,50 +199,135 @@ impl DnsClient {
             .map_err(LookupError::DnsProtocolError)?;
 
         let socket = self.bind()?;
-        rustix::net::sendto(&socket, &buffer, SendFlags::empty(), &self.server)
-            .map_err(LookupError::SocketError)?;
+        // a datagram socket never takes part of a message, it either fits the buffer or not
+        loop {
+            match rustix::net::send(&socket, &buffer, SendFlags::empty()) {
+                Ok(_) => break,
+                Err(Errno::AGAIN) => wait(&socket, PollFlags::OUT, deadline).await?,
+                Err(Errno::INTR) => {}
+                Err(err) => return Err(LookupError::SocketError(err)),
+            }
+        }
 
         let mut buffer = vec![0; 1024];
-        let (len, _) = rustix::net::recvfrom(&socket, &mut buffer, RecvFlags::empty())
-            .map_err(LookupError::SocketError)?;
-
-        let mut answers = [ResourceRecord::default(); 16];
-        let mut authority = [ResourceRecord::default(); 16];
-        let mut additional = [ResourceRecord::default(); 16];
-        let message = Message::read(
-            &buffer[..len],
-            &mut questions,
-            &mut answers,
-            &mut authority,
-            &mut additional,
-        )
-        .map_err(LookupError::DnsProtocolError)?;
-
-        fn process_answers(
-            answers: &[ResourceRecord],
-            addr_type: &AddrType,
-        ) -> Result<core::net::IpAddr> {
-            for answer in answers {
-                match (answer.data().len(), &addr_type) {
-                    (4, AddrType::IPv4 | AddrType::Either) => {
-                        let mut ip = [0u8; 4];
-                        ip.copy_from_slice(answer.data());
-                        let ip = core::net::Ipv4Addr::from(ip);
-                        return Ok(core::net::IpAddr::V4(ip));
-                    }
-                    (16, AddrType::IPv6 | AddrType::Either) => {
-                        let mut ip = [0u8; 16];
-                        ip.copy_from_slice(answer.data());
-                        let ip = core::net::Ipv6Addr::from(ip);
-                        return Ok(core::net::IpAddr::V6(ip));
-                    }
-                    _ => {}
+        loop {
+            let len = match rustix::net::recv(&socket, &mut buffer, RecvFlags::empty()) {
+                Ok(len) => len,
+                Err(Errno::AGAIN) => {
+                    wait(&socket, PollFlags::IN, deadline).await?;
+                    continue;
                 }
+                Err(Errno::INTR) => continue,
+                Err(err) => return Err(LookupError::SocketError(err)),
+            };
+
+            let mut answers = [ResourceRecord::default(); 16];
+            let mut authority = [ResourceRecord::default(); 16];
+            let mut additional = [ResourceRecord::default(); 16];
+            let message = Message::read(
+                &buffer[..len],
+                &mut questions,
+                &mut answers,
+                &mut authority,
+                &mut additional,
+            )
+            .map_err(LookupError::DnsProtocolError)?;
+            // not an answer to this query, ours may still come
+            if message.id() != id {
+                continue;
             }
-            Err(LookupError::NotFound)
+
+            let ips = message
+                .answers()
+                .iter()
+                .filter_map(|answer| address(answer, ty))
+                .collect();
+            return Ok(ips);
+        }
+    }
+}
+
+/// IPv6 and IPv4 addresses alternating, starting with IPv6
+fn interleave(v6: Vec<core::net::IpAddr>, v4: Vec<core::net::IpAddr>) -> Vec<core::net::IpAddr> {
+    let mut addrs = Vec::with_capacity(v6.len() + v4.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (ip6, ip4) => addrs.extend(ip6.into_iter().chain(ip4)),
         }
+    }
+    addrs
+}
+
+/// whether `ip` is of the family `addr_type` asks for
+fn of_type(ip: &core::net::IpAddr, addr_type: AddrType) -> bool {
+    match addr_type {
+        AddrType::IPv4 => ip.is_ipv4(),
+        AddrType::IPv6 => ip.is_ipv6(),
+        AddrType::Either => true,
+    }
+}
+
+/// `localhost` and its subdomains are loopback whatever else says so (RFC 6761),
+/// anything else is looked up in /etc/hosts
+fn local_addresses(name: &str) -> Vec<core::net::IpAddr> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    if name == "localhost" || name.ends_with(".localhost") {
+        return vec![
+            core::net::Ipv6Addr::LOCALHOST.into(),
+            core::net::Ipv4Addr::LOCALHOST.into(),
+        ];
+    }
 
-        process_answers(message.answers(), &addr_type)
+    match utils::read_file("/etc/hosts") {
+        Ok(data) => hosts_lookup(&String::from_utf8_lossy(&data), &name),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// the addresses of the lines in a hosts file that list `name`, as its canonical name or an alias
+fn hosts_lookup(hosts: &str, name: &str) -> Vec<core::net::IpAddr> {
+    hosts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('#').next()?.split_whitespace();
+            let ip = fields.next()?;
+            if !fields.any(|alias| alias.trim_end_matches('.').eq_ignore_ascii_case(name)) {
+                return None;
+            }
+            // a zone index like `fe80::1%eth0` cannot be passed on, as in resolv.conf
+            ip.split('%').next()?.parse().ok()
+        })
+        .collect()
+}
+
+/// `reactor::wait` with a missed deadline as `LookupError::TimedOut`
+async fn wait(socket: &OwnedFd, flags: PollFlags, deadline: u64) -> Result<()> {
+    reactor::wait(socket, flags, deadline)
+        .await
+        .map_err(|err| match err {
+            Errno::TIMEDOUT => LookupError::TimedOut,
+            err => LookupError::SocketError(err),
+        })
+}
+
+/// the address in `answer` if it is an `IN` record of the type asked for, a CNAME or anything
+/// else in the chain may happen to have data of the same length
+fn address(answer: &ResourceRecord<'_>, ty: ResourceType) -> Option<core::net::IpAddr> {
+    if answer.ty() != ty || answer.class() != CLASS_IN {
+        return None;
+    }
+    match ty {
+        ResourceType::A => {
+            let ip: [u8; 4] = answer.data().try_into().ok()?;
+            Some(core::net::IpAddr::V4(core::net::Ipv4Addr::from(ip)))
+        }
+        ResourceType::AAAA => {
+            let ip: [u8; 16] = answer.data().try_into().ok()?;
+            Some(core::net::IpAddr::V6(core::net::Ipv6Addr::from(ip)))
+        }
+        _ => None,
     }
 }
 
@@ -150,9 +339,8 @@ impl embedded_nal_async::Dns for DnsClient {
         host: &str,
         addr_type: embedded_nal_async::AddrType,
     ) -> core::result::Result<embedded_nal_async::IpAddr, Self::Error> {
-        
-
-        self.nslookup(host, addr_type).map(|res| match res {
+        let deadline = monotonic_nsecs().saturating_add(QUERY_TIMEOUT_NSECS);
+        self.nslookup(host, addr_type, deadline).await.map(|res| match res {
             core::net::IpAddr::V4(ip) => embedded_nal_async::IpAddr::V4(ip.octets().into()),
             core::net::IpAddr::V6(ip) => embedded_nal_async::IpAddr::V6(ip.octets().into()),
         })
@@ -165,3 +353,87 @@ impl embedded_nal_async::Dns for DnsClient {
         Ok(embedded_nal_async::heapless::String::from("unimplemented"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(ty: ResourceType, class: u16, data: &[u8]) -> ResourceRecord<'_> {
+        ResourceRecord::new("example.com", ty, class, 60, data)
+    }
+
+    #[test]
+    fn takes_addresses_of_the_type_asked_for() {
+        let a = record(ResourceType::A, CLASS_IN, &[192, 0, 2, 1]);
+        assert_eq!(
+            address(&a, ResourceType::A),
+            Some(core::net::IpAddr::V4([192, 0, 2, 1].into()))
+        );
+        assert_eq!(address(&a, ResourceType::AAAA), None);
+
+        let aaaa = record(ResourceType::AAAA, CLASS_IN, &[0x20; 16]);
+        assert_eq!(
+            address(&aaaa, ResourceType::AAAA),
+            Some(core::net::IpAddr::V6([0x20; 16].into()))
+        );
+    }
+
+    #[test]
+    fn localhost_never_asks_the_nameserver() {
+        let loopback: Vec<core::net::IpAddr> = vec![
+            core::net::Ipv6Addr::LOCALHOST.into(),
+            core::net::Ipv4Addr::LOCALHOST.into(),
+        ];
+        assert_eq!(local_addresses("localhost"), loopback);
+        assert_eq!(local_addresses("LocalHost."), loopback);
+        assert_eq!(local_addresses("settings.localhost"), loopback);
+    }
+
+    #[test]
+    fn finds_names_and_aliases_in_hosts() {
+        let hosts = "\
+# static entries
+192.0.2.10   settings.internal settings   # the settings server
+2001:db8::10 Settings.Internal
+fe80::1%eth0 router
+192.0.2.11   notsettings.internal
+";
+        let addrs = hosts_lookup(hosts, "settings.internal");
+        assert_eq!(
+            addrs,
+            [
+                core::net::IpAddr::V4([192, 0, 2, 10].into()),
+                "2001:db8::10".parse::<core::net::IpAddr>().unwrap(),
+            ]
+        );
+        assert_eq!(hosts_lookup(hosts, "settings").len(), 1);
+        assert_eq!(
+            hosts_lookup(hosts, "router"),
+            ["fe80::1".parse::<core::net::IpAddr>().unwrap()]
+        );
+        assert!(hosts_lookup(hosts, "the").is_empty());
+        assert!(hosts_lookup(hosts, "internal").is_empty());
+    }
+
+    #[test]
+    fn local_addresses_keep_to_the_family_asked_for() {
+        let v6 = core::net::IpAddr::V6(core::net::Ipv6Addr::LOCALHOST);
+        let v4 = core::net::IpAddr::V4(core::net::Ipv4Addr::LOCALHOST);
+        assert!(of_type(&v4, AddrType::IPv4) && !of_type(&v6, AddrType::IPv4));
+        assert!(of_type(&v6, AddrType::IPv6) && !of_type(&v4, AddrType::IPv6));
+        assert_eq!(interleave(vec![v6, v6], vec![v4]), [v6, v4, v6]);
+    }
+
+    #[test]
+    fn skips_other_records_of_the_same_length() {
+        // a CNAME target like `a.b` is 4 bytes on the wire as well
+        let cname = record(ResourceType::CName, CLASS_IN, &[1, b'a', 1, b'b']);
+        assert_eq!(address(&cname, ResourceType::A), None);
+
+        let chaos = record(ResourceType::A, 3, &[192, 0, 2, 1]);
+        assert_eq!(address(&chaos, ResourceType::A), None);
+
+        let short = record(ResourceType::A, CLASS_IN, &[192, 0, 2]);
+        assert_eq!(address(&short, ResourceType::A), None);
+    }
+}
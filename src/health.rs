@@ -0,0 +1,50 @@
+use alloc::vec::Vec;
+
+use crate::{
+    config,
+    utils::{monotonic_nsecs, spinlock::Mutex},
+};
+
+pub enum CrashVerdict {
+    Restart,
+    Rollback,
+}
+
+struct Crashes {
+    generation: u64,
+    timestamps: Vec<u64>,
+}
+
+/// crash history of the most recently crashing settings generation
+static CRASHES: Mutex<Crashes> = Mutex::new(Crashes {
+    generation: 0,
+    timestamps: Vec::new(),
+});
+
+/// records a crash of a child running with `generation` and decides whether
+/// to try again or give up on that generation
+pub fn record_crash(generation: u64) -> CrashVerdict {
+    let config = config::get();
+    let now = monotonic_nsecs();
+
+    let mut crashes = CRASHES.lock();
+    if crashes.generation != generation {
+        crashes.generation = generation;
+        crashes.timestamps.clear();
+    }
+    crashes
+        .timestamps
+        .retain(|crashed_at| now - crashed_at < config.rollback_window_nsecs);
+    crashes.timestamps.push(now);
+
+    if crashes.timestamps.len() >= config.rollback_max_crashes {
+        CrashVerdict::Rollback
+    } else {
+        CrashVerdict::Restart
+    }
+}
+
+pub fn has_crashed(generation: u64) -> bool {
+    let crashes = CRASHES.lock();
+    crashes.generation == generation && !crashes.timestamps.is_empty()
+}
@@ -0,0 +1,133 @@
+//! Headers for settings requests: `Accept-Encoding`, `Authorization: Bearer` from
+//! `RUBICON_SETTINGS_TOKEN_FILE` or `RUBICON_SETTINGS_API_KEY`, and the static
+//! `RUBICON_SETTINGS_HEADER_*` ones. Token files get rotated underneath us, so the file is read
+//! again whenever it is a different file than last time. The token only goes out over TLS, a
+//! unix socket or to loopback, never in the clear over the network. Loopback means a
+//! literal address, a name like `localhost` is only as local as the resolver says.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::{borrow::ToOwned, string::String, vec::Vec};
+use rustix::io::Errno;
+
+use super::{encoding, proxy, split_authority, HttpError};
+use crate::{
+    config, println,
+    utils::{self, spinlock::Mutex, FileId},
+};
+
+/// the token as of the last read and the file it came from
+struct CachedToken {
+    id: FileId,
+    token: Option<String>,
+}
+
+static TOKEN: Mutex<Option<CachedToken>> = Mutex::new(None);
+static WITHHELD_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// every header to send with a settings request to `url`, `credentials` is false once a redirect
+/// has left the origin the request was made to
+pub fn settings_headers(
+    url: &str,
+    credentials: bool,
+) -> Result<Vec<(String, String)>, HttpError> {
+    let config = config::get();
+    let mut headers = config.settings_headers.clone();
+    headers.push((
+        "Accept-Encoding".to_owned(),
+        encoding::ACCEPT_ENCODING.to_owned(),
+    ));
+
+    if credentials {
+        let token = match &config.settings_token_file {
+            Some(path) => token_from_file(path).map_err(HttpError::TokenFile)?,
+            None => config.settings_api_key.clone(),
+        };
+        match token {
+            Some(token) if protects_credentials(url) => {
+                headers.push(("Authorization".to_owned(), format!("Bearer {}", token)));
+            }
+            Some(_) => {
+                if !WITHHELD_WARNED.swap(true, Ordering::Relaxed) {
+                    println!("WARNING: not sending the settings token in the clear to {}", url);
+                }
+            }
+            None => {}
+        }
+    }
+
+    Ok(headers)
+}
+
+/// whether a token can go to `url`: encrypted, over a unix socket, or to a loopback address
+fn protects_credentials(url: &str) -> bool {
+    if url.starts_with("https://") || url.starts_with("unix://") {
+        return true;
+    }
+    let Some(rest) = url.strip_prefix("http://") else {
+        return false;
+    };
+    let authority = &rest[..rest.find(['/', '?', '#']).unwrap_or(rest.len())];
+    split_authority(authority).is_some_and(|(host, _)| proxy::is_loopback(host))
+}
+
+/// the form reqwless takes headers in
+pub fn borrowed(headers: &[(String, String)]) -> Vec<(&str, &str)> {
+    headers
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect()
+}
+
+fn token_from_file(path: &str) -> Result<Option<String>, Errno> {
+    let id = FileId::from(&rustix::fs::stat(path)?);
+
+    let mut cached = TOKEN.lock();
+    if let Some(cached) = &*cached {
+        if cached.id == id {
+            return Ok(cached.token.clone());
+        }
+    }
+
+    let data = utils::read_file(path)?;
+    let token = core::str::from_utf8(&data)
+        .ok()
+        .map(str::trim)
+        .filter(|token| !token.is_empty() && !token.bytes().any(|b| b.is_ascii_control()))
+        .map(ToOwned::to_owned);
+    match &token {
+        Some(_) => println!("read settings token from {}", path),
+        None => println!(
+            "settings token file {} holds no usable token, sending none",
+            path
+        ),
+    }
+
+    *cached = Some(CachedToken {
+        id,
+        token: token.clone(),
+    });
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_go_over_tls_unix_sockets_and_loopback() {
+        assert!(protects_credentials("https://settings.example.com/data.json"));
+        assert!(protects_credentials("unix:///run/agent.sock:/settings"));
+        assert!(protects_credentials("http://127.0.0.1/settings"));
+        assert!(protects_credentials("http://[::1]:8080/settings"));
+    }
+
+    #[test]
+    fn credentials_are_withheld_in_the_clear() {
+        assert!(!protects_credentials("http://settings.example.com/data.json"));
+        assert!(!protects_credentials("http://10.0.0.1/settings"));
+        assert!(!protects_credentials("http://localhost:8080/settings"));
+        assert!(!protects_credentials("http://localhost.example.com/settings"));
+        assert!(!protects_credentials("ftp://localhost/settings"));
+    }
+}
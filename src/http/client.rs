@@ -0,0 +1,198 @@
+//! A client for one settings url that lives as long as the source polling it. The receive buffer,
+//! the resolved addresses and the connection itself, TLS session included, are kept between
+//! polls, so steady state polling is one request on an open connection.
+
+use alloc::{rc::Rc, string::String, vec::Vec};
+use embedded_nal_async::{AddrType, Dns, SocketAddr, TcpConnect};
+use reqwless::{
+    client::HttpConnection,
+    request::{Request, RequestBuilder},
+};
+
+use super::{
+    auth, header, redirect::Redirects, settings_from_response, HttpConfig, HttpError,
+    RustixTCP, RustixTcpConnection,
+};
+use crate::{println, settings::RemoteSettings};
+
+type Connection = HttpConnection<'static, RustixTcpConnection>;
+
+const RX_BUF_SIZE: usize = 8_096;
+
+pub struct SettingsClient {
+    url: String,
+    /// shared with the future of the request being sent, `block_on` wants it to own what it uses
+    transport: Rc<RustixTCP>,
+    /// the open connection, it owns its socket and TLS session and is handed to every request
+    conn: Option<Connection>,
+    /// what the connection goes to, `Target::host` and `Target::port`
+    host: String,
+    port: u16,
+    /// what goes on the request line, see `Target::request_target`
+    path: String,
+    /// `Proxy-Authorization` for a proxy that forwards the requests
+    proxy_authorization: Option<String>,
+    rx_buf: Vec<u8>,
+    /// `ETag` of the last accepted document, sent as `If-None-Match` so an unchanged one is a 304
+    etag: Option<String>,
+}
+
+enum Fetched {
+    Settings(RemoteSettings, Option<String>),
+    Redirect(String),
+}
+
+impl SettingsClient {
+    pub fn new(url: &str) -> Result<Self, HttpError> {
+        let config = HttpConfig::new();
+        let target = config.target(url)?;
+        config.transport.set_target(&target);
+
+        Ok(Self {
+            url: url.into(),
+            transport: Rc::new(config.transport),
+            conn: None,
+            path: target.request_target().into(),
+            proxy_authorization: target
+                .proxy_authorization()
+                .map(|(_, authorization)| authorization.into()),
+            host: target.host,
+            port: target.port,
+            rx_buf: vec![0; RX_BUF_SIZE],
+            etag: None,
+        })
+    }
+
+    /// the current document, `HttpError::NotModified` when it is the one fetched last time
+    pub fn fetch(&mut self) -> Result<RemoteSettings, HttpError> {
+        let reused = self.conn.is_some();
+        let res = match self.fetch_once() {
+            // the server may have closed an idle connection since the last poll,
+            // an answer it did give is not retried
+            Err(err) if reused && !answered(&err) => {
+                println!(
+                    "kept alive connection to {} failed, reconnecting: {:?}",
+                    self.host, err
+                );
+                self.fetch_once()
+            }
+            res => res,
+        };
+
+        match res {
+            Ok(Fetched::Settings(settings, etag)) => {
+                self.etag = etag;
+                Ok(settings)
+            }
+            Err(err) => {
+                if matches!(err, HttpError::NoConfig) {
+                    self.etag = None;
+                }
+                Err(err)
+            }
+            // redirects are rare for settings, they are followed without keep-alive
+            Ok(Fetched::Redirect(location)) => {
+                self.etag = None;
+                let mut redirects = Redirects::new(&self.url);
+                redirects.follow(location)?;
+                super::download_settings(redirects)
+            }
+        }
+    }
+
+    fn fetch_once(&mut self) -> Result<Fetched, HttpError> {
+        self.transport.load_client_identity()?;
+        let mut headers = auth::settings_headers(&self.url, true)?;
+        if let Some(etag) = &self.etag {
+            headers.push(("If-None-Match".into(), etag.clone()));
+        }
+        if let Some(authorization) = &self.proxy_authorization {
+            headers.push(("Proxy-Authorization".into(), authorization.clone()));
+        }
+
+        let transport = self.transport.clone();
+        let conn = self.conn.take();
+        let rx_buf = core::mem::take(&mut self.rx_buf);
+        let host = self.host.clone();
+        let port = self.port;
+        let path = self.path.clone();
+        let redirects = Redirects::new(&self.url);
+
+        let out = super::block_on(async move {
+            let mut rx_buf = rx_buf;
+            transport.start_request();
+
+            let mut conn = match conn {
+                Some(conn) => conn,
+                None => match connect(&transport, &host, port).await {
+                    Ok(conn) => conn,
+                    Err(err) => return (None, rx_buf, Err(transport.explain(err))),
+                },
+            };
+
+            let res = async {
+                let headers = auth::borrowed(&headers);
+                let request = Request::get(&path).host(&host).headers(&headers).build();
+                let response = conn
+                    .send(request, &mut rx_buf)
+                    .await
+                    .map_err(HttpError::from)?;
+
+                if let Some(location) = redirects.location(response.status, response.headers())? {
+                    return Ok(Fetched::Redirect(location));
+                }
+                let etag = header(response.headers(), "etag").map(String::from);
+                let settings = settings_from_response(response).await?;
+                Ok(Fetched::Settings(settings, etag))
+            }
+            .await;
+
+            // after a failed or redirected exchange the state of the connection is unknown,
+            // a 304 has no body so nothing of it is left unread
+            let conn = match res {
+                Ok(Fetched::Settings(..))
+                | Err(HttpError::InvalidSettings(_) | HttpError::NotModified) => Some(conn),
+                _ => None,
+            };
+            (conn, rx_buf, res.map_err(|err| transport.explain(err)))
+        });
+        let (conn, rx_buf, res) = match out {
+            Ok(out) => out,
+            Err(err) => {
+                // the buffer went down with the future
+                self.rx_buf = vec![0; RX_BUF_SIZE];
+                return Err(err);
+            }
+        };
+
+        self.conn = conn;
+        self.rx_buf = rx_buf;
+        res
+    }
+}
+
+/// what `HttpClient::resource` does, except that the connection it opens is ours to keep
+async fn connect(transport: &RustixTCP, host: &str, port: u16) -> Result<Connection, HttpError> {
+    let ip = transport
+        .get_host_by_name(host, AddrType::Either)
+        .await
+        .map_err(|_| reqwless::Error::Dns)?;
+    let conn = transport
+        .connect(SocketAddr::new(ip, port))
+        .await
+        .map_err(|err| reqwless::Error::Network(embedded_io_async::Error::kind(&err)))?;
+    Ok(HttpConnection::Plain(conn))
+}
+
+/// whether the server got to answer, then a retry on a fresh connection would not help
+fn answered(err: &HttpError) -> bool {
+    matches!(
+        err,
+        HttpError::InvalidSettings(_)
+            | HttpError::Status(_)
+            | HttpError::NotModified
+            | HttpError::NoConfig
+            | HttpError::RateLimited(_)
+            | HttpError::Unavailable(_)
+    )
+}
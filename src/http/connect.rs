@@ -0,0 +1,147 @@
+//! Connection establishment across every resolved address, RFC 8305 "happy eyeballs" style:
+//! attempts are started in the resolver's order with a head start each, the first to complete wins.
+
+use core::net::{IpAddr, SocketAddr};
+
+use alloc::vec::Vec;
+use rustix::{
+    event::{poll, PollFd, PollFlags},
+    fd::OwnedFd,
+    io::Errno,
+    net::{ipproto, sockopt, AddressFamily, SocketAddrUnix, SocketFlags, SocketType},
+};
+
+use super::{reactor, RustixTCPError};
+use crate::utils;
+
+/// how long an attempt runs on its own before the next address is tried, RFC 8305 recommends 250ms
+const CONNECTION_ATTEMPT_DELAY_NSECS: u64 = 250 * utils::NANOSECONDS_PER_MILLISECOND;
+/// pause before connecting to a unix socket with a full backlog again
+const UNIX_CONNECT_RETRY_NSECS: u64 = 10 * utils::NANOSECONDS_PER_MILLISECOND;
+
+enum Attempt {
+    Connected(OwnedFd),
+    InProgress(OwnedFd),
+}
+
+/// connects to the first of `addrs` that answers on `port` before `deadline`,
+/// the returned socket is non-blocking
+pub async fn connect(
+    addrs: &[IpAddr],
+    port: u16,
+    deadline: u64,
+) -> Result<OwnedFd, RustixTCPError> {
+    let mut pending = addrs.iter().map(|ip| SocketAddr::new(*ip, port));
+    let mut attempts: Vec<OwnedFd> = Vec::new();
+    let mut last_err = RustixTCPError::InvalidAddress;
+    let mut start_next = true;
+
+    loop {
+        if start_next {
+            match pending.next() {
+                Some(addr) => match start(&addr) {
+                    Ok(Attempt::Connected(socket)) => return Ok(socket),
+                    Ok(Attempt::InProgress(socket)) => attempts.push(socket),
+                    Err(err) => {
+                        last_err = err;
+                        continue;
+                    }
+                },
+                None if attempts.is_empty() => return Err(last_err),
+                None => {}
+            }
+        }
+
+        reactor::remaining_ms(deadline).ok_or(RustixTCPError::ConnectTimeout)?;
+        let head_start = match pending.len() {
+            0 => deadline,
+            _ => deadline.min(utils::monotonic_nsecs() + CONNECTION_ATTEMPT_DELAY_NSECS),
+        };
+        match reactor::wait_any(&attempts, PollFlags::OUT, head_start).await {
+            // a head start running out is no failure, the overall deadline is checked above
+            Ok(()) | Err(Errno::TIMEDOUT) => {}
+            Err(err) => return Err(RustixTCPError::Errno(err)),
+        }
+
+        let mut fds: Vec<PollFd<'_>> = attempts
+            .iter()
+            .map(|socket| PollFd::new(socket, PollFlags::OUT))
+            .collect();
+        match poll(&mut fds, 0) {
+            Ok(_) => {}
+            Err(Errno::INTR) => {
+                start_next = false;
+                continue;
+            }
+            Err(err) => return Err(RustixTCPError::Errno(err)),
+        }
+        let done: Vec<usize> = fds
+            .iter()
+            .enumerate()
+            .filter(|(_, fd)| !fd.revents().is_empty())
+            .map(|(i, _)| i)
+            .collect();
+        drop(fds);
+
+        // nothing finished within the head start: give the next address a go as well,
+        // an attempt that failed frees its slot right away
+        start_next = true;
+        for i in done.into_iter().rev() {
+            let socket = attempts.swap_remove(i);
+            match sockopt::get_socket_error(&socket) {
+                Ok(Ok(())) => return Ok(socket),
+                Ok(Err(err)) | Err(err) => last_err = RustixTCPError::Errno(err),
+            }
+        }
+    }
+}
+
+/// connects to the unix socket at `path` before `deadline`, the returned socket is non-blocking
+pub async fn connect_unix(path: &str, deadline: u64) -> Result<OwnedFd, RustixTCPError> {
+    let addr = SocketAddrUnix::new(path).map_err(|_| RustixTCPError::InvalidAddress)?;
+    let socket = rustix::net::socket_with(
+        AddressFamily::UNIX,
+        SocketType::STREAM,
+        SocketFlags::NONBLOCK | SocketFlags::CLOEXEC,
+        None,
+    )
+    .map_err(RustixTCPError::Errno)?;
+
+    loop {
+        match rustix::net::connect_unix(&socket, &addr) {
+            Ok(()) => return Ok(socket),
+            // the listen backlog is full, unlike TCP nothing is pending, so ask again
+            Err(Errno::AGAIN) => {
+                reactor::remaining_ms(deadline).ok_or(RustixTCPError::ConnectTimeout)?;
+                let retry = utils::monotonic_nsecs() + UNIX_CONNECT_RETRY_NSECS;
+                reactor::sleep_until(retry.min(deadline)).await;
+            }
+            Err(Errno::INTR) => {}
+            Err(err) => return Err(RustixTCPError::Errno(err)),
+        }
+    }
+}
+
+fn start(addr: &SocketAddr) -> Result<Attempt, RustixTCPError> {
+    let family = match addr {
+        SocketAddr::V4(_) => AddressFamily::INET,
+        SocketAddr::V6(_) => AddressFamily::INET6,
+    };
+    let socket = rustix::net::socket_with(
+        family,
+        SocketType::STREAM,
+        SocketFlags::NONBLOCK | SocketFlags::CLOEXEC,
+        Some(ipproto::TCP),
+    )
+    .map_err(RustixTCPError::Errno)?;
+
+    let res = match addr {
+        SocketAddr::V4(addr) => rustix::net::connect_v4(&socket, addr),
+        SocketAddr::V6(addr) => rustix::net::connect_v6(&socket, addr),
+    };
+    match res {
+        Ok(()) => Ok(Attempt::Connected(socket)),
+        Err(Errno::INPROGRESS) => Ok(Attempt::InProgress(socket)),
+        Err(err) => Err(RustixTCPError::Errno(err)),
+    }
+}
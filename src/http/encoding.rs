@@ -0,0 +1,352 @@
+//! `Content-Encoding: gzip` and `deflate` bodies, inflated chunk by chunk as they arrive so an agent
+//! is never held compressed and uncompressed in memory at once. A gzip body may hold several
+//! members one after the other, like `gzip -c a b` writes them, anything else after the end of
+//! the stream is refused.
+
+use alloc::{boxed::Box, string::ToString, vec::Vec};
+use miniz_oxide::{
+    inflate::stream::{inflate, InflateState},
+    DataFormat, MZError, MZFlush, MZStatus,
+};
+
+use super::{header, HttpError};
+
+/// what we ask servers to compress with
+pub const ACCEPT_ENCODING: &str = "gzip, deflate";
+
+const INFLATE_BUFFER_SIZE: usize = 32 * 1024;
+
+const GZIP_MAGIC: [u8; 3] = [0x1f, 0x8b, 8];
+const GZIP_FHCRC: u8 = 1 << 1;
+const GZIP_FEXTRA: u8 = 1 << 2;
+const GZIP_FNAME: u8 = 1 << 3;
+const GZIP_FCOMMENT: u8 = 1 << 4;
+const GZIP_TRAILER_LEN: usize = 8;
+/// a gzip header longer than this is not one we want to buffer, its name or comment is made up
+const MAX_PENDING_LEN: usize = 64 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    /// zlib wrapped, which is what `deflate` means in HTTP
+    Deflate,
+}
+
+impl Encoding {
+    pub fn from_headers<'h>(
+        headers: impl Iterator<Item = (&'h str, &'h [u8])>,
+    ) -> Result<Self, HttpError> {
+        match header(headers, "content-encoding") {
+            None => Ok(Encoding::Identity),
+            Some(encoding) if encoding.eq_ignore_ascii_case("identity") => Ok(Encoding::Identity),
+            Some(encoding) if encoding.eq_ignore_ascii_case("gzip") => Ok(Encoding::Gzip),
+            Some(encoding) if encoding.eq_ignore_ascii_case("x-gzip") => Ok(Encoding::Gzip),
+            Some(encoding) if encoding.eq_ignore_ascii_case("deflate") => Ok(Encoding::Deflate),
+            Some(encoding) => Err(HttpError::UnsupportedEncoding(encoding.to_string())),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum Stage {
+    Header,
+    Body,
+    Trailer,
+    /// a whole stream, or for gzip a whole member, is through
+    Done,
+}
+
+/// undoes the `Content-Encoding` of a body fed to it in arbitrary chunks
+pub struct Decoder {
+    encoding: Encoding,
+    stage: Stage,
+    /// `None` for identity, there is nothing to inflate
+    inflate: Option<Box<InflateState>>,
+    /// gzip header or trailer bytes seen so far
+    pending: Vec<u8>,
+    crc: crc32fast::Hasher,
+    size: u32,
+    buf: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn new(encoding: Encoding) -> Self {
+        let (stage, format) = match encoding {
+            Encoding::Gzip => (Stage::Header, Some(DataFormat::Raw)),
+            Encoding::Deflate => (Stage::Body, Some(DataFormat::Zlib)),
+            Encoding::Identity => (Stage::Body, None),
+        };
+
+        Self {
+            encoding,
+            stage,
+            inflate: format.map(InflateState::new_boxed),
+            pending: Vec::new(),
+            crc: crc32fast::Hasher::new(),
+            size: 0,
+            buf: match format {
+                Some(_) => vec![0; INFLATE_BUFFER_SIZE],
+                None => Vec::new(),
+            },
+        }
+    }
+
+    /// decodes the next chunk of the body, handing whatever comes out of it to `out`
+    pub fn update<F>(&mut self, input: &[u8], out: &mut F) -> Result<(), HttpError>
+    where
+        F: FnMut(&[u8]) -> Result<(), HttpError>,
+    {
+        match self.stage {
+            Stage::Body => self.inflate(input, out)?,
+            _ => self.pending.extend_from_slice(input),
+        }
+        self.drain_pending(out)
+    }
+
+    /// checks that the body was complete, gzip trailers are checked as they arrive
+    pub fn finish(self) -> Result<(), HttpError> {
+        match (self.encoding, &self.stage) {
+            (Encoding::Identity, _) | (_, Stage::Done) => Ok(()),
+            _ => Err(HttpError::Decompression),
+        }
+    }
+
+    /// moves through the gzip headers and trailers in `pending` as far as it can
+    fn drain_pending<F>(&mut self, out: &mut F) -> Result<(), HttpError>
+    where
+        F: FnMut(&[u8]) -> Result<(), HttpError>,
+    {
+        loop {
+            match self.stage {
+                Stage::Body => return Ok(()),
+                Stage::Header => {
+                    let Some(len) = gzip_header_len(&self.pending)? else {
+                        if self.pending.len() > MAX_PENDING_LEN {
+                            return Err(HttpError::Decompression);
+                        }
+                        return Ok(());
+                    };
+                    let body = self.pending.split_off(len);
+                    self.pending.clear();
+                    self.stage = Stage::Body;
+                    self.inflate(&body, out)?;
+                }
+                Stage::Trailer => {
+                    if self.pending.len() < GZIP_TRAILER_LEN {
+                        return Ok(());
+                    }
+                    let trailer = &self.pending[..GZIP_TRAILER_LEN];
+                    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+                    let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+                    if crc != core::mem::take(&mut self.crc).finalize() || size != self.size {
+                        return Err(HttpError::Decompression);
+                    }
+                    self.pending.drain(..GZIP_TRAILER_LEN);
+                    self.stage = Stage::Done;
+                }
+                Stage::Done if self.pending.is_empty() => return Ok(()),
+                // only gzip goes on after the end, with the next member
+                Stage::Done if self.encoding != Encoding::Gzip => {
+                    return Err(HttpError::Decompression);
+                }
+                Stage::Done => {
+                    if let Some(state) = &mut self.inflate {
+                        state.reset(DataFormat::Raw);
+                    }
+                    self.size = 0;
+                    self.stage = Stage::Header;
+                }
+            }
+        }
+    }
+
+    fn inflate<F>(&mut self, mut input: &[u8], out: &mut F) -> Result<(), HttpError>
+    where
+        F: FnMut(&[u8]) -> Result<(), HttpError>,
+    {
+        let Some(state) = &mut self.inflate else {
+            return out(input);
+        };
+
+        loop {
+            let res = inflate(state, input, &mut self.buf, MZFlush::None);
+            input = &input[res.bytes_consumed..];
+
+            let data = &self.buf[..res.bytes_written];
+            if !data.is_empty() {
+                self.crc.update(data);
+                self.size = self.size.wrapping_add(data.len() as u32);
+                out(data)?;
+            }
+
+            match res.status {
+                Ok(MZStatus::StreamEnd) => {
+                    self.stage = match self.encoding {
+                        Encoding::Gzip => Stage::Trailer,
+                        _ => Stage::Done,
+                    };
+                    self.pending.extend_from_slice(input);
+                    return Ok(());
+                }
+                // everything buffered was written out, more input is needed to go on
+                Ok(_) | Err(MZError::Buf) => {}
+                Err(_) => return Err(HttpError::Decompression),
+            }
+
+            // a full buffer means there may be more output waiting for room
+            if input.is_empty() && res.bytes_written < self.buf.len() {
+                return Ok(());
+            }
+            if res.bytes_consumed == 0 && res.bytes_written == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// the whole of a small body, at most `max_bytes` of it once decoded
+pub fn decode_all(encoding: Encoding, body: &[u8], max_bytes: usize) -> Result<Vec<u8>, HttpError> {
+    let mut decoded = Vec::new();
+    let mut decoder = Decoder::new(encoding);
+    decoder.update(body, &mut |data| {
+        if decoded.len() + data.len() > max_bytes {
+            return Err(HttpError::BodyTooLarge(decoded.len() + data.len()));
+        }
+        decoded.extend_from_slice(data);
+        Ok(())
+    })?;
+    decoder.finish()?;
+    Ok(decoded)
+}
+
+/// length of the gzip member header `data` starts with, `None` while it is incomplete
+fn gzip_header_len(data: &[u8]) -> Result<Option<usize>, HttpError> {
+    if data.len() < 10 {
+        return Ok(None);
+    }
+    if data[..3] != GZIP_MAGIC {
+        return Err(HttpError::Decompression);
+    }
+
+    let flags = data[3];
+    let mut len = 10;
+    if flags & GZIP_FEXTRA != 0 {
+        let Some(xlen) = data.get(len..len + 2) else {
+            return Ok(None);
+        };
+        len += 2 + u16::from_le_bytes([xlen[0], xlen[1]]) as usize;
+    }
+    for flag in [GZIP_FNAME, GZIP_FCOMMENT] {
+        if flags & flag == 0 {
+            continue;
+        }
+        // zero terminated
+        match data
+            .get(len..)
+            .and_then(|rest| rest.iter().position(|b| *b == 0))
+        {
+            Some(end) => len += end + 1,
+            None => return Ok(None),
+        }
+    }
+    if flags & GZIP_FHCRC != 0 {
+        len += 2;
+    }
+
+    if data.len() < len {
+        return Ok(None);
+    }
+    Ok(Some(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO_GZ: [u8; 26] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb, 0x48, 0xcd, 0xc9, 0xc9,
+        0x57, 0x00, 0x00, 0xf6, 0xf9, 0x81, 0xed, 0x06, 0x00, 0x00, 0x00,
+    ];
+    const WORLD_GZ: [u8; 25] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x2b, 0xcf, 0x2f, 0xca, 0x49,
+        0x01, 0x00, 0x43, 0x11, 0x77, 0x3a, 0x05, 0x00, 0x00, 0x00,
+    ];
+    /// "hello" with `FNAME` set to `a.txt`
+    const NAMED_GZ: [u8; 31] = [
+        0x1f, 0x8b, 0x08, 0x08, 0x00, 0x00, 0x00, 0x00, 0x02, 0xff, 0x61, 0x2e, 0x74, 0x78, 0x74,
+        0x00, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00, 0x86, 0xa6, 0x10, 0x36, 0x05, 0x00, 0x00,
+        0x00,
+    ];
+    const HELLO_WORLD_ZLIB: [u8; 19] = [
+        0x78, 0x9c, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0x28, 0xcf, 0x2f, 0xca, 0x49, 0x01, 0x00,
+        0x1a, 0x0b, 0x04, 0x5d,
+    ];
+
+    /// feeds `body` in chunks of `chunk` bytes
+    fn decode(encoding: Encoding, body: &[u8], chunk: usize) -> Result<Vec<u8>, HttpError> {
+        let mut decoded = Vec::new();
+        let mut decoder = Decoder::new(encoding);
+        for input in body.chunks(chunk) {
+            decoder.update(input, &mut |data| {
+                decoded.extend_from_slice(data);
+                Ok(())
+            })?;
+        }
+        decoder.finish()?;
+        Ok(decoded)
+    }
+
+    #[test]
+    fn measures_gzip_headers() {
+        assert_eq!(gzip_header_len(&HELLO_GZ).unwrap(), Some(10));
+        assert_eq!(gzip_header_len(&NAMED_GZ).unwrap(), Some(16));
+        assert_eq!(gzip_header_len(&NAMED_GZ[..12]).unwrap(), None);
+        assert_eq!(gzip_header_len(&HELLO_GZ[..9]).unwrap(), None);
+        assert!(gzip_header_len(&HELLO_WORLD_ZLIB[..10]).is_err());
+    }
+
+    #[test]
+    fn decodes_in_any_chunking() {
+        for chunk in [1, 3, 64] {
+            assert_eq!(decode(Encoding::Gzip, &NAMED_GZ, chunk).unwrap(), b"hello");
+            let decoded = decode(Encoding::Deflate, &HELLO_WORLD_ZLIB, chunk).unwrap();
+            assert_eq!(decoded, b"hello world");
+            assert_eq!(decode(Encoding::Identity, b"plain", chunk).unwrap(), b"plain");
+        }
+    }
+
+    #[test]
+    fn decodes_concatenated_gzip_members() {
+        let body = [HELLO_GZ.as_slice(), WORLD_GZ.as_slice()].concat();
+        for chunk in [1, 7, 64] {
+            assert_eq!(decode(Encoding::Gzip, &body, chunk).unwrap(), b"hello world");
+        }
+    }
+
+    #[test]
+    fn refuses_trailing_data() {
+        let body = [HELLO_GZ.as_slice(), b"garbage!!!!"].concat();
+        assert!(decode(Encoding::Gzip, &body, 64).is_err());
+        let body = [HELLO_WORLD_ZLIB.as_slice(), b"x"].concat();
+        assert!(decode(Encoding::Deflate, &body, 64).is_err());
+    }
+
+    #[test]
+    fn refuses_truncated_and_corrupted_bodies() {
+        assert!(decode(Encoding::Gzip, &HELLO_GZ[..HELLO_GZ.len() - 1], 64).is_err());
+        assert!(decode(Encoding::Gzip, &[], 64).is_err());
+
+        let mut corrupted = HELLO_GZ;
+        corrupted[HELLO_GZ.len() - 8] ^= 1;
+        assert!(decode(Encoding::Gzip, &corrupted, 64).is_err());
+    }
+
+    #[test]
+    fn refuses_endless_gzip_headers() {
+        let mut body = HELLO_GZ[..10].to_vec();
+        body[3] = GZIP_FCOMMENT;
+        body.resize(MAX_PENDING_LEN + 11, b'a');
+        assert!(matches!(decode(Encoding::Gzip, &body, 4096), Err(HttpError::Decompression)));
+    }
+}
@@ -0,0 +1,104 @@
+//! Continuing cut off downloads: what is already in the sink is kept and only the rest is asked
+//! for with `Range`, guarded by `If-Range` so a different version of the file starts over.
+
+use alloc::string::String;
+use rustix::{fd::OwnedFd, io::Errno};
+
+use super::{header, HttpError};
+use crate::integrity::Verifier;
+
+const REHASH_CHUNK_SIZE: usize = 64 * 1024;
+
+/// how much of which version of a file a sink holds
+#[derive(Clone, Debug)]
+pub struct Progress {
+    /// strong `ETag` of the version, weak ones can't be used with `If-Range`
+    pub etag: String,
+    pub len: usize,
+}
+
+/// a download that failed, with what it left in the sink when that can be continued
+#[derive(Debug)]
+pub struct Interrupted {
+    pub err: HttpError,
+    pub progress: Option<Progress>,
+}
+
+/// whether the bytes written before `err` can be built upon, they can't when they are what failed
+pub fn resumable(err: &HttpError) -> bool {
+    !matches!(
+        err,
+        HttpError::Integrity(_)
+            | HttpError::BodyTooLarge(_)
+            | HttpError::Decompression
+            | HttpError::UnexpectedRange
+            | HttpError::Errno(_)
+    )
+}
+
+/// the `ETag` of a response if it is a strong one
+pub fn strong_etag<'h>(headers: impl Iterator<Item = (&'h str, &'h [u8])>) -> Option<&'h str> {
+    header(headers, "etag").filter(|etag| !etag.starts_with("W/"))
+}
+
+/// where the body of a 206 starts, from `Content-Range: bytes 100-999/1000`
+pub fn content_range_start<'h>(
+    headers: impl Iterator<Item = (&'h str, &'h [u8])>,
+) -> Option<usize> {
+    let range = header(headers, "content-range")?.strip_prefix("bytes ")?;
+    let (start, _) = range.split_once('-')?;
+    start.trim().parse().ok()
+}
+
+/// feeds the first `len` bytes already in `sink` to `verifier`, the digest covers the whole file
+pub fn rehash(sink: &OwnedFd, len: usize, verifier: &mut Verifier) -> Result<(), Errno> {
+    let mut buf = vec![0; REHASH_CHUNK_SIZE.min(len)];
+    let mut offset = 0;
+    while offset < len {
+        let want = buf.len().min(len - offset);
+        let read = rustix::io::pread(sink, &mut buf[..want], offset as u64)?;
+        if read == 0 {
+            // the sink is shorter than its progress says
+            return Err(Errno::IO);
+        }
+        verifier.update(&buf[..read]);
+        offset += read;
+    }
+    Ok(())
+}
+
+/// writes all of `data` at `offset`, independent of the file position other fds share
+pub fn write_all_at(sink: &OwnedFd, mut data: &[u8], mut offset: usize) -> Result<(), Errno> {
+    while !data.is_empty() {
+        let written = rustix::io::pwrite(sink, data, offset as u64)?;
+        data = &data[written..];
+        offset += written;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers<'h>(name: &'h str, value: &'h str) -> impl Iterator<Item = (&'h str, &'h [u8])> {
+        [(name, value.as_bytes())].into_iter()
+    }
+
+    #[test]
+    fn content_range_start_of_206_responses() {
+        assert_eq!(content_range_start(headers("Content-Range", "bytes 100-999/1000")), Some(100));
+        assert_eq!(content_range_start(headers("content-range", "bytes 0-0/*")), Some(0));
+        assert_eq!(content_range_start(headers("Content-Range", "bytes */1000")), None);
+        assert_eq!(content_range_start(headers("Content-Range", "items 1-2/3")), None);
+        assert_eq!(content_range_start(headers("ETag", "\"abc\"")), None);
+    }
+
+    #[test]
+    fn only_strong_etags_guard_a_resume() {
+        assert_eq!(strong_etag(headers("ETag", "\"abc\"")), Some("\"abc\""));
+        assert_eq!(strong_etag(headers("etag", " \"abc\" ")), Some("\"abc\""));
+        assert_eq!(strong_etag(headers("ETag", "W/\"abc\"")), None);
+        assert_eq!(strong_etag(core::iter::empty()), None);
+    }
+}
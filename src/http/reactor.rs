@@ -0,0 +1,209 @@
+//! Readiness waits for the non-blocking sockets of the transport, on pasts.
+//!
+//! A socket that is not ready makes its future register the fd with the waker and return
+//! `Pending`. Once no task of the executor can make progress, `ReactorPark` polls every fd
+//! registered on the thread, bounded by the earliest deadline, and wakes the tasks again.
+
+use core::{
+    cell::RefCell,
+    future::{poll_fn, Future},
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Poll, Waker},
+};
+
+use alloc::{sync::Arc, task::Wake, vec::Vec};
+use pasts::{LocalBoxNotify, Park, Pool};
+use rustix::{
+    event::{poll, PollFd, PollFlags},
+    fd::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    io::Errno,
+    process::Pid,
+};
+
+use crate::utils::{monotonic_nsecs, spinlock::Mutex, NANOSECONDS_PER_MILLISECOND};
+
+/// what a pending future waits for, `fd` is `None` for a plain timer
+struct Registration {
+    thread: Pid,
+    fd: Option<RawFd>,
+    flags: PollFlags,
+    deadline: u64,
+    waker: Waker,
+}
+
+/// every executor parks on its own thread, so a registration belongs to the thread it was made on
+static REGISTRATIONS: Mutex<Vec<Registration>> = Mutex::new(Vec::new());
+
+/// milliseconds left until `deadline` (monotonic nanoseconds), `None` once it passed
+pub fn remaining_ms(deadline: u64) -> Option<i32> {
+    let now = monotonic_nsecs();
+    if now >= deadline {
+        return None;
+    }
+    // round up, a 0ms poll would spin until the deadline
+    let ms = (deadline - now).div_ceil(NANOSECONDS_PER_MILLISECOND);
+    Some(ms.min(i32::MAX as u64) as i32)
+}
+
+/// resolves once `fd` is ready for `flags`, errors and hangups count as ready so the following
+/// send or recv can report them, `Errno::TIMEDOUT` once `deadline` passes
+pub fn wait(
+    fd: &impl AsFd,
+    flags: PollFlags,
+    deadline: u64,
+) -> impl Future<Output = Result<(), Errno>> + '_ {
+    wait_any(core::slice::from_ref(fd), flags, deadline)
+}
+
+/// `wait` for the first of `fds` to become ready
+pub fn wait_any<F: AsFd>(
+    fds: &[F],
+    flags: PollFlags,
+    deadline: u64,
+) -> impl Future<Output = Result<(), Errno>> + '_ {
+    poll_fn(move |cx| {
+        let mut ready: Vec<PollFd<'_>> = fds.iter().map(|fd| PollFd::new(fd, flags)).collect();
+        match poll(&mut ready, 0) {
+            Ok(0) | Err(Errno::INTR) => {}
+            Ok(_) => return Poll::Ready(Ok(())),
+            Err(err) => return Poll::Ready(Err(err)),
+        }
+        if remaining_ms(deadline).is_none() {
+            return Poll::Ready(Err(Errno::TIMEDOUT));
+        }
+
+        let thread = rustix::thread::gettid();
+        let mut registrations = REGISTRATIONS.lock();
+        for fd in fds {
+            registrations.push(Registration {
+                thread,
+                fd: Some(fd.as_fd().as_raw_fd()),
+                flags,
+                deadline,
+                waker: cx.waker().clone(),
+            });
+        }
+        Poll::Pending
+    })
+}
+
+/// resolves once `deadline` passed
+pub fn sleep_until(deadline: u64) -> impl Future<Output = ()> {
+    poll_fn(move |cx| {
+        if remaining_ms(deadline).is_none() {
+            return Poll::Ready(());
+        }
+        REGISTRATIONS.lock().push(Registration {
+            thread: rustix::thread::gettid(),
+            fd: None,
+            flags: PollFlags::empty(),
+            deadline,
+            waker: cx.waker().clone(),
+        });
+        Poll::Pending
+    })
+}
+
+/// drops what futures of this thread registered and never got to wait for, the fds may be
+/// closed and reused by now
+pub fn forget() {
+    let thread = rustix::thread::gettid();
+    REGISTRATIONS
+        .lock()
+        .retain(|registration| registration.thread != thread);
+}
+
+/// waits for what the tasks of this thread registered, then wakes all of them
+fn park_thread() {
+    let thread = rustix::thread::gettid();
+    let waiting: Vec<Registration> = {
+        let mut registrations = REGISTRATIONS.lock();
+        let (waiting, others) = core::mem::take(&mut *registrations)
+            .into_iter()
+            .partition(|registration| registration.thread == thread);
+        *registrations = others;
+        waiting
+    };
+    if waiting.is_empty() {
+        return;
+    }
+
+    let deadline = waiting
+        .iter()
+        .map(|registration| registration.deadline)
+        .min()
+        .unwrap_or(u64::MAX);
+    // SAFETY: the fds were registered by pending futures that still own them, they are
+    // only dropped once the task runs again, which is after this poll
+    let borrowed: Vec<(BorrowedFd<'_>, PollFlags)> = waiting
+        .iter()
+        .filter_map(|registration| {
+            let fd = unsafe { BorrowedFd::borrow_raw(registration.fd?) };
+            Some((fd, registration.flags))
+        })
+        .collect();
+    let mut fds: Vec<PollFd<'_>> = borrowed
+        .iter()
+        .map(|(fd, flags)| PollFd::new(fd, *flags))
+        .collect();
+    if let Some(timeout) = remaining_ms(deadline) {
+        // readiness and interruption alike end the park, the tasks check for themselves
+        let _ = poll(&mut fds, timeout);
+    }
+    drop(fds);
+    drop(borrowed);
+
+    for registration in waiting {
+        registration.waker.wake();
+    }
+}
+
+/// parks in `park_thread`, a wake from the same thread just skips the next park
+#[derive(Default)]
+pub struct ReactorPark {
+    woken: AtomicBool,
+}
+
+impl Wake for ReactorPark {
+    fn wake(self: Arc<Self>) {
+        self.woken.store(true, Ordering::SeqCst);
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Park for ReactorPark {
+    fn park(&self) {
+        if self.woken.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        park_thread();
+        // the wakes above are for the poll the executor does next anyway
+        self.woken.store(false, Ordering::SeqCst);
+    }
+}
+
+/// the tasks of one `block_on`, parked on the reactor
+#[derive(Default)]
+pub struct ReactorPool {
+    spawning: RefCell<Vec<LocalBoxNotify<'static>>>,
+}
+
+impl Pool for ReactorPool {
+    type Park = ReactorPark;
+
+    fn push(&self, task: LocalBoxNotify<'static>) {
+        self.spawning.borrow_mut().push(task);
+    }
+
+    fn drain(&self, tasks: &mut Vec<LocalBoxNotify<'static>>) -> bool {
+        let mut spawning = self.spawning.borrow_mut();
+        if spawning.is_empty() {
+            return false;
+        }
+        tasks.append(&mut spawning);
+        true
+    }
+}
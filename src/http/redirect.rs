@@ -0,0 +1,208 @@
+//! Redirect following. A response borrows the client and the buffer it was read into, so callers
+//! loop over the hops themselves and ask `Redirects` at every response whether it is final.
+
+use alloc::{
+    borrow::ToOwned,
+    string::{String, ToString},
+};
+use reqwless::response::Status;
+
+use super::HttpError;
+use crate::println;
+
+const MAX_REDIRECTS: usize = 10;
+
+/// the redirect chain of one request
+pub struct Redirects {
+    pub url: String,
+    followed: usize,
+    /// `scheme://authority` of the url the chain started at
+    origin: String,
+}
+
+impl Redirects {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_owned(),
+            followed: 0,
+            origin: origin(url).to_owned(),
+        }
+    }
+
+    /// whether the chain is still on the origin it started at, credentials are only sent there
+    pub fn same_origin(&self) -> bool {
+        origin(&self.url).eq_ignore_ascii_case(&self.origin)
+    }
+
+    /// where a response with `status` and `headers` sends us next, `None` when it is final
+    pub fn location<'h>(
+        &self,
+        status: Status,
+        headers: impl Iterator<Item = (&'h str, &'h [u8])>,
+    ) -> Result<Option<String>, HttpError> {
+        if !matches!(
+            status,
+            Status::MovedPermanently
+                | Status::Found
+                | Status::SeeOther
+                | Status::TemporaryRedirect
+                | Status::PermanentRedirect
+        ) {
+            return Ok(None);
+        }
+
+        let location = headers
+            .filter(|(name, _)| name.eq_ignore_ascii_case("location"))
+            .find_map(|(_, value)| core::str::from_utf8(value).ok())
+            .map(str::trim)
+            .filter(|location| !location.is_empty())
+            .ok_or(HttpError::RedirectLocationMissing)?;
+
+        Ok(Some(resolve(&self.url, location)))
+    }
+
+    /// moves on to `location`
+    pub fn follow(&mut self, location: String) -> Result<(), HttpError> {
+        self.followed += 1;
+        if self.followed > MAX_REDIRECTS {
+            return Err(HttpError::TooManyRedirects(MAX_REDIRECTS));
+        }
+        // a unix socket may send us elsewhere on itself, but nobody may send us to a socket
+        let same_socket = location.starts_with("unix://") && origin(&location) == origin(&self.url);
+        if !location.starts_with("https://") && !location.starts_with("http://") && !same_socket {
+            return Err(HttpError::InvalidRedirect(location));
+        }
+        if self.url.starts_with("https://") && !location.starts_with("https://") {
+            return Err(HttpError::InsecureRedirect(location));
+        }
+
+        println!("Redirected to: {:?}", location);
+        self.url = location;
+        Ok(())
+    }
+}
+
+/// only 307 and 308 ask for the request to be repeated as is, after the others it would be a GET
+pub fn preserves_method(status: Status) -> bool {
+    matches!(
+        status,
+        Status::TemporaryRedirect | Status::PermanentRedirect
+    )
+}
+
+/// `scheme://authority` of `url`, for `unix://` urls the socket stands in for the authority
+fn origin(url: &str) -> &str {
+    split_path(url).0
+}
+
+/// `https://host/a?b` into `https://host` and `/a?b`, `unix:///run/agent.sock:/a` into
+/// `unix:///run/agent.sock` and `/a`
+fn split_path(url: &str) -> (&str, &str) {
+    if let Some(rest) = url.strip_prefix("unix://") {
+        let socket_len = rest.find(':').unwrap_or(rest.len());
+        let (origin, path) = url.split_at("unix://".len() + socket_len);
+        return (origin, path.strip_prefix(':').unwrap_or(path));
+    }
+
+    let authority_start = url.find("://").map(|i| i + 3).unwrap_or(0);
+    let authority_len = url[authority_start..]
+        .find(['/', '?', '#'])
+        .unwrap_or(url.len() - authority_start);
+    url.split_at(authority_start + authority_len)
+}
+
+/// resolves `location` against `base` the way RFC 3986 does for the forms servers send
+fn resolve(base: &str, location: &str) -> String {
+    if let Some(scheme) = scheme(location) {
+        // `follow` compares schemes as written
+        return format!("{}{}", scheme.to_ascii_lowercase(), &location[scheme.len()..]);
+    }
+
+    let (scheme, _) = base.split_once("://").unwrap_or(("https", base));
+    if let Some(network_path) = location.strip_prefix("//") {
+        return format!("{}://{}", scheme, network_path);
+    }
+
+    // on a unix socket only the path after the `:` can change
+    let (origin, path) = split_path(base);
+    let origin = match scheme {
+        "unix" => format!("{}:", origin),
+        _ => origin.to_string(),
+    };
+    if location.starts_with('/') {
+        return format!("{}{}", origin, location);
+    }
+
+    // a query replaces the one of the current path, a fragment only the fragment
+    let without_fragment = path.split('#').next().unwrap_or_default();
+    let without_query = without_fragment.split('?').next().unwrap_or_default();
+    if location.starts_with('?') {
+        return format!("{}{}{}", origin, without_query, location);
+    }
+    if location.starts_with('#') {
+        return format!("{}{}{}", origin, without_fragment, location);
+    }
+
+    // relative to the directory of the current path
+    let directory = match without_query.rfind('/') {
+        Some(slash) => &without_query[..=slash],
+        None => "/",
+    };
+    format!("{}{}{}", origin, directory, location)
+}
+
+/// the scheme `location` starts with, `ALPHA *( ALPHA / DIGIT / "+" / "-" / "." ) ":"` ahead of
+/// any `/`, `?` or `#`, a relative path like `a:b/c` needs a `./` in front to not be one
+fn scheme(location: &str) -> Option<&str> {
+    let colon = location.find(':')?;
+    let scheme = &location[..colon];
+    let mut chars = scheme.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    valid.then_some(scheme)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_origin_and_path() {
+        assert_eq!(split_path("https://host/a?b"), ("https://host", "/a?b"));
+        assert_eq!(split_path("https://host?b"), ("https://host", "?b"));
+        assert_eq!(split_path("https://host"), ("https://host", ""));
+        assert_eq!(split_path("http://host:8080#f"), ("http://host:8080", "#f"));
+        assert_eq!(
+            split_path("unix:///run/agent.sock:/a"),
+            ("unix:///run/agent.sock", "/a")
+        );
+        assert_eq!(split_path("unix:///run/agent.sock"), ("unix:///run/agent.sock", ""));
+    }
+
+    #[test]
+    fn resolves_absolute_locations() {
+        let base = "https://host/dir/file?q";
+        assert_eq!(resolve(base, "http://other/x"), "http://other/x");
+        assert_eq!(resolve(base, "HTTPS://other/x"), "https://other/x");
+        assert_eq!(resolve(base, "mailto:someone"), "mailto:someone");
+        assert_eq!(resolve(base, "//other/x"), "https://other/x");
+    }
+
+    #[test]
+    fn resolves_relative_locations() {
+        let base = "https://host/dir/file?q#f";
+        assert_eq!(resolve(base, "/x"), "https://host/x");
+        assert_eq!(resolve(base, "x"), "https://host/dir/x");
+        assert_eq!(resolve(base, "x?a=b://c"), "https://host/dir/x?a=b://c");
+        assert_eq!(resolve(base, "?r"), "https://host/dir/file?r");
+        assert_eq!(resolve(base, "#g"), "https://host/dir/file?q#g");
+        assert_eq!(resolve("https://host", "x"), "https://host/x");
+    }
+
+    #[test]
+    fn resolves_on_the_same_unix_socket() {
+        let base = "unix:///run/agent.sock:/dir/file";
+        assert_eq!(resolve(base, "/x"), "unix:///run/agent.sock:/x");
+        assert_eq!(resolve(base, "x"), "unix:///run/agent.sock:/dir/x");
+    }
+}
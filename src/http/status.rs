@@ -0,0 +1,125 @@
+//! What the status of a final response means. Anything outside of 2xx ends up as an `HttpError`,
+//! for settings with the answers the poller acts on, 304, 404, 429 and 503, kept apart.
+
+use reqwless::response::Status;
+
+use super::{header, HttpError};
+
+/// a server asking for more than this is still asked again after an hour
+const MAX_RETRY_AFTER_SECS: u64 = 60 * 60;
+
+/// anything but a 2xx is an error once redirects have been dealt with, `Unknown` statuses are 0
+pub fn check(status: Status) -> Result<(), HttpError> {
+    match status as u16 {
+        200..=299 => Ok(()),
+        code => Err(HttpError::Status(code)),
+    }
+}
+
+/// like `check`, but with the answers a settings poller treats differently told apart
+pub fn check_settings<'h>(
+    status: Status,
+    headers: impl Iterator<Item = (&'h str, &'h [u8])>,
+) -> Result<(), HttpError> {
+    match status as u16 {
+        200..=299 => Ok(()),
+        304 => Err(HttpError::NotModified),
+        404 => Err(HttpError::NoConfig),
+        429 => Err(HttpError::RateLimited(retry_after_secs(headers))),
+        503 => Err(HttpError::Unavailable(retry_after_secs(headers))),
+        code => Err(HttpError::Status(code)),
+    }
+}
+
+/// `Retry-After` in seconds from now, it is either a number of seconds or an HTTP date
+fn retry_after_secs<'h>(headers: impl Iterator<Item = (&'h str, &'h [u8])>) -> Option<u64> {
+    let value = header(headers, "retry-after")?;
+
+    let secs = match value.parse::<u64>() {
+        Ok(secs) => secs,
+        Err(_) => {
+            let now = rustix::time::clock_gettime(rustix::time::ClockId::Realtime).tv_sec as u64;
+            parse_http_date(value)?.saturating_sub(now)
+        }
+    };
+    Some(secs.min(MAX_RETRY_AFTER_SECS))
+}
+
+/// seconds since the epoch of an IMF-fixdate like `Sun, 06 Nov 1994 08:49:37 GMT`,
+/// the obsolete formats RFC 9110 still allows are not sent by anything we talk to
+fn parse_http_date(date: &str) -> Option<u64> {
+    let (_, date) = date.split_once(", ")?;
+    let mut parts = date.split(' ');
+    let day: u64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let year: u64 = parts.next()?.parse().ok()?;
+    let time = parts.next()?;
+    if parts.next()? != "GMT" {
+        return None;
+    }
+
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let month = MONTHS.iter().position(|m| *m == month)? as u64 + 1;
+
+    let mut time = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+    if year < 1970 || day == 0 || day > 31 || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+
+    Some(days_from_civil(year, month, day) * 86_400 + hours * 3_600 + minutes * 60 + seconds)
+}
+
+/// days since 1970-01-01 of a date in the proleptic Gregorian calendar, from 1970 on
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    // years start in March, so the leap day is the last day of the year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_days_from_the_epoch() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(days_from_civil(2024, 2, 29), 19_782);
+        assert_eq!(days_from_civil(2024, 3, 1), 19_783);
+    }
+
+    #[test]
+    fn parses_imf_fixdates() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784_111_777));
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+    }
+
+    #[test]
+    fn rejects_other_dates() {
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 CET"), None);
+        assert_eq!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 24:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 00 Nov 1994 08:49:37 GMT"), None);
+    }
+
+    #[test]
+    fn retry_after_in_seconds_is_capped() {
+        let headers = |value: &'static [u8]| [("Retry-After", value)].into_iter();
+        assert_eq!(retry_after_secs(headers(b"120")), Some(120));
+        assert_eq!(retry_after_secs(headers(b"86400")), Some(MAX_RETRY_AFTER_SECS));
+        assert_eq!(retry_after_secs(headers(b"soon")), None);
+        // a date in the past means now
+        assert_eq!(retry_after_secs(headers(b"Sun, 06 Nov 1994 08:49:37 GMT")), Some(0));
+        assert_eq!(retry_after_secs(core::iter::empty()), None);
+    }
+}
@@ -0,0 +1,470 @@
+//! Server certificate verification for embedded-tls: the chain is checked against the trust
+//! anchors and the host with webpki, the signatures are checked with the RustCrypto algorithms
+//! below, embedded-tls has no verifier of its own that does all of that.
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::time::Duration;
+
+use embedded_tls::{
+    CertificateEntryRef, CertificateRef, HandshakeVerifyRef, SignatureScheme, TlsCipherSuite,
+    TlsError, TlsVerifier,
+};
+use rustls_pki_types::{
+    alg_id, AlgorithmIdentifier, CertificateDer, InvalidSignature, ServerName,
+    SignatureVerificationAlgorithm, TrustAnchor, UnixTime,
+};
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use webpki::{EndEntityCert, KeyUsage};
+
+use crate::println;
+
+/// TLS 1.3 signs the handshake transcript behind this prefix, RFC 8446 section 4.4.3
+const SERVER_CERTIFICATE_VERIFY_CONTEXT: &[u8] = b"TLS 1.3, server CertificateVerify\0";
+/// an RSA key shorter than this is refused, as webpki does with its own backends
+const MIN_RSA_MODULUS_BYTES: usize = 2048 / 8;
+
+pub struct Verifier {
+    roots: Arc<Vec<TrustAnchor<'static>>>,
+    host: String,
+    /// RUBICON_TLS_INSECURE, whatever the server presents is accepted
+    insecure: bool,
+    /// RUBICON_TLS_PINS, digests one of which the key of the leaf has to have
+    pins: Option<Vec<[u8; 32]>>,
+    /// the leaf of the verified chain, the key of the `CertificateVerify` that follows
+    leaf: Option<Vec<u8>>,
+    /// transcript hash up to the certificate, what `CertificateVerify` has to sign
+    transcript: Option<Vec<u8>>,
+}
+
+impl Verifier {
+    pub fn new(
+        roots: Arc<Vec<TrustAnchor<'static>>>,
+        host: &str,
+        pins: Option<Vec<[u8; 32]>>,
+    ) -> Self {
+        Self {
+            roots,
+            host: host.into(),
+            insecure: false,
+            pins,
+            leaf: None,
+            transcript: None,
+        }
+    }
+
+    pub fn insecure(host: &str) -> Self {
+        Self {
+            insecure: true,
+            ..Self::new(Arc::default(), host, None)
+        }
+    }
+}
+
+impl<CipherSuite: TlsCipherSuite> TlsVerifier<CipherSuite> for Verifier {
+    fn set_hostname_verification(&mut self, hostname: &str) -> Result<(), TlsError> {
+        self.host = hostname.into();
+        Ok(())
+    }
+
+    fn verify_certificate(
+        &mut self,
+        transcript: &CipherSuite::Hash,
+        cert: CertificateRef,
+    ) -> Result<(), TlsError> {
+        if self.insecure {
+            return Ok(());
+        }
+        let mut certs = cert.entries.iter().filter_map(|entry| match entry {
+            CertificateEntryRef::X509(der) => Some(CertificateDer::from(*der)),
+            CertificateEntryRef::RawPublicKey(_) => None,
+        });
+        let leaf = certs.next().ok_or(TlsError::InvalidCertificate)?;
+        let intermediates: Vec<CertificateDer<'_>> = certs.collect();
+
+        if let Err(err) = verify_chain(&self.roots, &self.host, &leaf, &intermediates) {
+            println!(
+                "certificate of {} failed verification: {:?}",
+                self.host, err
+            );
+            return Err(TlsError::InvalidCertificate);
+        }
+        if let Some(pins) = &self.pins {
+            if !pinned(pins, &leaf) {
+                println!("certificate of {} matches none of RUBICON_TLS_PINS", self.host);
+                return Err(TlsError::InvalidCertificate);
+            }
+        }
+
+        self.leaf = Some(leaf.to_vec());
+        self.transcript = Some(Digest::finalize(transcript.clone()).to_vec());
+        Ok(())
+    }
+
+    fn verify_signature(&mut self, verify: HandshakeVerifyRef) -> Result<(), TlsError> {
+        if self.insecure {
+            return Ok(());
+        }
+        let (Some(leaf), Some(transcript)) = (&self.leaf, &self.transcript) else {
+            return Err(TlsError::InvalidCertificate);
+        };
+        let algorithm =
+            tls13_algorithm(verify.signature_scheme).ok_or(TlsError::InvalidSignatureScheme)?;
+
+        let mut message = Vec::with_capacity(64 + SERVER_CERTIFICATE_VERIFY_CONTEXT.len() + 64);
+        message.resize(64, 0x20);
+        message.extend_from_slice(SERVER_CERTIFICATE_VERIFY_CONTEXT);
+        message.extend_from_slice(transcript);
+
+        let leaf = CertificateDer::from(leaf.as_slice());
+        let cert = EndEntityCert::try_from(&leaf).map_err(|_| TlsError::InvalidCertificate)?;
+        cert.verify_signature(algorithm, &message, verify.signature)
+            .map_err(|err| {
+                println!("handshake signature of {} is invalid: {:?}", self.host, err);
+                TlsError::InvalidSignature
+            })
+    }
+}
+
+fn verify_chain(
+    roots: &[TrustAnchor<'static>],
+    host: &str,
+    leaf: &CertificateDer<'_>,
+    intermediates: &[CertificateDer<'_>],
+) -> Result<(), webpki::Error> {
+    let cert = EndEntityCert::try_from(leaf)?;
+    let now = rustix::time::clock_gettime(rustix::time::ClockId::Realtime);
+    let now = UnixTime::since_unix_epoch(Duration::from_secs(now.tv_sec as u64));
+
+    cert.verify_for_usage(
+        ALL_ALGORITHMS,
+        roots,
+        intermediates,
+        now,
+        KeyUsage::server_auth(),
+        None,
+        None,
+    )?;
+
+    let name = ServerName::try_from(host).map_err(|_| webpki::Error::MalformedDnsIdentifier)?;
+    cert.verify_is_valid_for_subject_name(&name)
+}
+
+/// whether the SHA-256 of the `SubjectPublicKeyInfo` of `cert` is one of `pins`
+fn pinned(pins: &[[u8; 32]], cert: &[u8]) -> bool {
+    let Some(spki) = subject_public_key_info(cert) else {
+        return false;
+    };
+    let digest: [u8; 32] = Sha256::digest(spki).into();
+    pins.contains(&digest)
+}
+
+const DER_SEQUENCE: u8 = 0x30;
+/// `[0] EXPLICIT Version`, left out for v1 certificates
+const DER_VERSION: u8 = 0xa0;
+
+/// the DER of the `SubjectPublicKeyInfo` of `cert`, tag and length included, RFC 5280 4.1
+pub fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    let (_, cert, _) = der_next(cert).filter(|(tag, ..)| *tag == DER_SEQUENCE)?;
+    let (_, tbs, _) = der_next(cert).filter(|(tag, ..)| *tag == DER_SEQUENCE)?;
+
+    let (tag, _, mut fields) = der_next(tbs)?;
+    if tag != DER_VERSION {
+        fields = tbs;
+    }
+    // serialNumber, signature, issuer, validity and subject come first
+    for _ in 0..5 {
+        fields = der_next(fields)?.2;
+    }
+    let (tag, contents, rest) = der_next(fields)?;
+    let encoded = &fields[..fields.len() - rest.len()];
+    (tag == DER_SEQUENCE && !contents.is_empty()).then_some(encoded)
+}
+
+/// the tag and contents of the first DER element of `der`, and what follows it
+fn der_next(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = der.split_first()?;
+    let (&len, rest) = rest.split_first()?;
+    let (len, rest) = match len {
+        0..=0x7f => (len as usize, rest),
+        0x81..=0x84 => {
+            let (len, rest) = rest.split_at_checked((len & 0x7f) as usize)?;
+            (len.iter().fold(0, |acc, b| acc << 8 | *b as usize), rest)
+        }
+        _ => return None,
+    };
+    let (contents, rest) = rest.split_at_checked(len)?;
+    Some((tag, contents, rest))
+}
+
+/// the algorithm behind a TLS 1.3 signature scheme, PKCS#1 is not allowed there anymore
+fn tls13_algorithm(scheme: SignatureScheme) -> Option<&'static dyn SignatureVerificationAlgorithm> {
+    Some(match scheme {
+        SignatureScheme::EcdsaSecp256r1Sha256 => &ECDSA_P256_SHA256,
+        SignatureScheme::EcdsaSecp384r1Sha384 => &ECDSA_P384_SHA384,
+        SignatureScheme::RsaPssRsaeSha256 => &RSA_PSS_SHA256,
+        SignatureScheme::RsaPssRsaeSha384 => &RSA_PSS_SHA384,
+        SignatureScheme::RsaPssRsaeSha512 => &RSA_PSS_SHA512,
+        SignatureScheme::Ed25519 => &ED25519,
+        _ => return None,
+    })
+}
+
+/// what certificates in a chain may be signed with
+static ALL_ALGORITHMS: &[&dyn SignatureVerificationAlgorithm] = &[
+    &ECDSA_P256_SHA256,
+    &ECDSA_P256_SHA384,
+    &ECDSA_P384_SHA256,
+    &ECDSA_P384_SHA384,
+    &RSA_PKCS1_SHA256,
+    &RSA_PKCS1_SHA384,
+    &RSA_PKCS1_SHA512,
+    &RSA_PSS_SHA256,
+    &RSA_PSS_SHA384,
+    &RSA_PSS_SHA512,
+    &ED25519,
+];
+
+static ECDSA_P256_SHA256: Algorithm = Algorithm::ecdsa(Curve::P256, HashAlg::Sha256);
+static ECDSA_P256_SHA384: Algorithm = Algorithm::ecdsa(Curve::P256, HashAlg::Sha384);
+static ECDSA_P384_SHA256: Algorithm = Algorithm::ecdsa(Curve::P384, HashAlg::Sha256);
+static ECDSA_P384_SHA384: Algorithm = Algorithm::ecdsa(Curve::P384, HashAlg::Sha384);
+static RSA_PKCS1_SHA256: Algorithm = Algorithm::rsa(Padding::Pkcs1, HashAlg::Sha256);
+static RSA_PKCS1_SHA384: Algorithm = Algorithm::rsa(Padding::Pkcs1, HashAlg::Sha384);
+static RSA_PKCS1_SHA512: Algorithm = Algorithm::rsa(Padding::Pkcs1, HashAlg::Sha512);
+static RSA_PSS_SHA256: Algorithm = Algorithm::rsa(Padding::Pss, HashAlg::Sha256);
+static RSA_PSS_SHA384: Algorithm = Algorithm::rsa(Padding::Pss, HashAlg::Sha384);
+static RSA_PSS_SHA512: Algorithm = Algorithm::rsa(Padding::Pss, HashAlg::Sha512);
+static ED25519: Algorithm = Algorithm {
+    kind: Kind::Ed25519,
+    public_key_alg_id: alg_id::ED25519,
+    signature_alg_id: alg_id::ED25519,
+};
+
+#[derive(Debug, Clone, Copy)]
+enum Curve {
+    P256,
+    P384,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Padding {
+    Pkcs1,
+    Pss,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum HashAlg {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl HashAlg {
+    fn digest(self, message: &[u8]) -> Vec<u8> {
+        match self {
+            HashAlg::Sha256 => Sha256::digest(message).to_vec(),
+            HashAlg::Sha384 => Sha384::digest(message).to_vec(),
+            HashAlg::Sha512 => Sha512::digest(message).to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Ecdsa(Curve, HashAlg),
+    Rsa(Padding, HashAlg),
+    Ed25519,
+}
+
+struct Algorithm {
+    kind: Kind,
+    public_key_alg_id: AlgorithmIdentifier,
+    signature_alg_id: AlgorithmIdentifier,
+}
+
+impl Algorithm {
+    const fn ecdsa(curve: Curve, hash: HashAlg) -> Self {
+        Self {
+            kind: Kind::Ecdsa(curve, hash),
+            public_key_alg_id: match curve {
+                Curve::P256 => alg_id::ECDSA_P256,
+                Curve::P384 => alg_id::ECDSA_P384,
+            },
+            signature_alg_id: match hash {
+                HashAlg::Sha256 => alg_id::ECDSA_SHA256,
+                HashAlg::Sha384 => alg_id::ECDSA_SHA384,
+                HashAlg::Sha512 => alg_id::ECDSA_SHA512,
+            },
+        }
+    }
+
+    const fn rsa(padding: Padding, hash: HashAlg) -> Self {
+        Self {
+            kind: Kind::Rsa(padding, hash),
+            public_key_alg_id: alg_id::RSA_ENCRYPTION,
+            signature_alg_id: match (padding, hash) {
+                (Padding::Pkcs1, HashAlg::Sha256) => alg_id::RSA_PKCS1_SHA256,
+                (Padding::Pkcs1, HashAlg::Sha384) => alg_id::RSA_PKCS1_SHA384,
+                (Padding::Pkcs1, HashAlg::Sha512) => alg_id::RSA_PKCS1_SHA512,
+                (Padding::Pss, HashAlg::Sha256) => alg_id::RSA_PSS_SHA256,
+                (Padding::Pss, HashAlg::Sha384) => alg_id::RSA_PSS_SHA384,
+                (Padding::Pss, HashAlg::Sha512) => alg_id::RSA_PSS_SHA512,
+            },
+        }
+    }
+}
+
+impl core::fmt::Debug for Algorithm {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("Algorithm").field(&self.kind).finish()
+    }
+}
+
+impl SignatureVerificationAlgorithm for Algorithm {
+    fn verify_signature(
+        &self,
+        public_key: &[u8],
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<(), InvalidSignature> {
+        let verified = match self.kind {
+            Kind::Ecdsa(curve, hash) => {
+                verify_ecdsa(curve, public_key, &hash.digest(message), signature)
+            }
+            Kind::Rsa(padding, hash) => verify_rsa(padding, hash, public_key, message, signature),
+            Kind::Ed25519 => verify_ed25519(public_key, message, signature),
+        };
+        verified.ok_or(InvalidSignature)
+    }
+
+    fn public_key_alg_id(&self) -> AlgorithmIdentifier {
+        self.public_key_alg_id
+    }
+
+    fn signature_alg_id(&self) -> AlgorithmIdentifier {
+        self.signature_alg_id
+    }
+}
+
+/// `public_key` is a SEC1 point, `signature` the DER `Ecdsa-Sig-Value` both X.509 and TLS use
+fn verify_ecdsa(curve: Curve, public_key: &[u8], prehash: &[u8], signature: &[u8]) -> Option<()> {
+    use p256::ecdsa::signature::hazmat::PrehashVerifier;
+
+    match curve {
+        Curve::P256 => {
+            let key = p256::ecdsa::VerifyingKey::from_sec1_bytes(public_key).ok()?;
+            let signature = p256::ecdsa::Signature::from_der(signature).ok()?;
+            key.verify_prehash(prehash, &signature).ok()
+        }
+        Curve::P384 => {
+            let key = p384::ecdsa::VerifyingKey::from_sec1_bytes(public_key).ok()?;
+            let signature = p384::ecdsa::Signature::from_der(signature).ok()?;
+            key.verify_prehash(prehash, &signature).ok()
+        }
+    }
+}
+
+/// `public_key` is a PKCS#1 `RSAPublicKey`
+fn verify_rsa(
+    padding: Padding,
+    hash: HashAlg,
+    public_key: &[u8],
+    message: &[u8],
+    signature: &[u8],
+) -> Option<()> {
+    use rsa::{pkcs1::DecodeRsaPublicKey, traits::PublicKeyParts, Pkcs1v15Sign, Pss};
+
+    let key = rsa::RsaPublicKey::from_pkcs1_der(public_key).ok()?;
+    if key.size() < MIN_RSA_MODULUS_BYTES {
+        return None;
+    }
+
+    let hashed = hash.digest(message);
+    let verified = match (padding, hash) {
+        (Padding::Pkcs1, HashAlg::Sha256) => {
+            key.verify(Pkcs1v15Sign::new::<Sha256>(), &hashed, signature)
+        }
+        (Padding::Pkcs1, HashAlg::Sha384) => {
+            key.verify(Pkcs1v15Sign::new::<Sha384>(), &hashed, signature)
+        }
+        (Padding::Pkcs1, HashAlg::Sha512) => {
+            key.verify(Pkcs1v15Sign::new::<Sha512>(), &hashed, signature)
+        }
+        (Padding::Pss, HashAlg::Sha256) => key.verify(Pss::new::<Sha256>(), &hashed, signature),
+        (Padding::Pss, HashAlg::Sha384) => key.verify(Pss::new::<Sha384>(), &hashed, signature),
+        (Padding::Pss, HashAlg::Sha512) => key.verify(Pss::new::<Sha512>(), &hashed, signature),
+    };
+    verified.ok()
+}
+
+fn verify_ed25519(public_key: &[u8], message: &[u8], signature: &[u8]) -> Option<()> {
+    let key = ed25519_compact::PublicKey::from_slice(public_key).ok()?;
+    let signature = ed25519_compact::Signature::from_slice(signature).ok()?;
+    key.verify(message, &signature).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tls13_has_no_pkcs1() {
+        assert!(tls13_algorithm(SignatureScheme::RsaPkcs1Sha256).is_none());
+        assert!(tls13_algorithm(SignatureScheme::RsaPssRsaeSha256).is_some());
+        assert!(tls13_algorithm(SignatureScheme::EcdsaSecp256r1Sha256).is_some());
+    }
+
+    #[test]
+    fn ed25519_signatures_verify() {
+        let key = ed25519_compact::KeyPair::from_seed(ed25519_compact::Seed::new([7; 32]));
+        let signature = key.sk.sign(b"message", None);
+
+        assert!(ED25519
+            .verify_signature(key.pk.as_ref(), b"message", signature.as_ref())
+            .is_ok());
+        assert!(ED25519
+            .verify_signature(key.pk.as_ref(), b"massage", signature.as_ref())
+            .is_err());
+    }
+
+    // openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -subj /CN=pin.test
+    const PINNED_CERT: &[u8] = b"-----BEGIN CERTIFICATE-----
+MIIBezCCASGgAwIBAgIUQgjI/CbT2THXCCvm+E+hJ6gTUHkwCgYIKoZIzj0EAwIw
+EzERMA8GA1UEAwwIcGluLnRlc3QwHhcNMjYxMDE5MDcwNjM5WhcNMzYxMDE2MDcw
+NjM5WjATMREwDwYDVQQDDAhwaW4udGVzdDBZMBMGByqGSM49AgEGCCqGSM49AwEH
+A0IABFnLSN2JElqBADdrOWGcvJN88GhwD8yt86tKiOg4L4aeEPMy47O8RA7KtaQ/
+Bv9O2PN9XBFSZJeVJfdikvDDzE6jUzBRMB0GA1UdDgQWBBQi54OgB+Tapi1S+kVX
+ldZ/CSaz3zAfBgNVHSMEGDAWgBQi54OgB+Tapi1S+kVXldZ/CSaz3zAPBgNVHRMB
+Af8EBTADAQH/MAoGCCqGSM49BAMCA0gAMEUCIQCngRRFm81xRPiVbUr9YhoPkXBd
+TtyJnzp51lGuzBwY9AIgX66WmWl0Crjn/za0ruwa2LthDdlPnDRCECVSCGeuBGY=
+-----END CERTIFICATE-----
+";
+    // openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256
+    const PIN: &str = "17c33fe87b7795c8d7907e026527c9854d2579e7db305ca0c201c00f10621b82";
+
+    #[test]
+    fn pinned_key_matches() {
+        let cert = &crate::http::pem::blocks(PINNED_CERT, "CERTIFICATE")[0];
+        let pin = crate::utils::hex_decode(PIN).unwrap();
+        assert!(pinned(&[[0; 32], pin], cert));
+    }
+
+    #[test]
+    fn other_keys_do_not_match() {
+        let cert = &crate::http::pem::blocks(PINNED_CERT, "CERTIFICATE")[0];
+        let mut pin: [u8; 32] = crate::utils::hex_decode(PIN).unwrap();
+        pin[31] ^= 1;
+        assert!(!pinned(&[pin], cert));
+        assert!(!pinned(&[], cert));
+        assert!(!pinned(&[pin], &cert[..40]));
+    }
+
+    #[test]
+    fn garbage_keys_do_not_verify() {
+        for algorithm in ALL_ALGORITHMS {
+            assert!(algorithm
+                .verify_signature(&[4; 65], b"message", &[0; 64])
+                .is_err());
+        }
+    }
+}
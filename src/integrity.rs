@@ -0,0 +1,119 @@
+//! Integrity of downloaded artifacts: a SHA-256 computed while the bytes stream in is compared
+//! against the digest from settings, and with `RUBICON_AGENT_PUBLIC_KEY` configured the digest
+//! also has to carry a valid ed25519 signature.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::string::String;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config, println,
+    settings::{RemoteSettings, SettingsError},
+    utils,
+};
+
+static UNVERIFIED_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// what a downloaded artifact has to hash to
+#[derive(Debug, Clone)]
+pub struct Expected {
+    sha256: [u8; 32],
+    signature: Option<[u8; 64]>,
+}
+
+impl Expected {
+    /// the expectation for the java agent of `settings`, `None` when the settings carry no digest
+    pub fn java_agent(settings: &RemoteSettings) -> Result<Option<Expected>, SettingsError> {
+        let Some(sha256) = &settings.java_agent_sha256 else {
+            if settings.java_agent_signature.is_some() {
+                return Err(SettingsError::SignatureWithoutDigest);
+            }
+            return Ok(None);
+        };
+
+        let sha256 =
+            utils::hex_decode(sha256).ok_or_else(|| SettingsError::InvalidDigest(sha256.clone()))?;
+        let signature = match &settings.java_agent_signature {
+            Some(signature) => Some(
+                utils::hex_decode(signature)
+                    .ok_or_else(|| SettingsError::InvalidSignature(signature.clone()))?,
+            ),
+            None => None,
+        };
+
+        Ok(Some(Expected { sha256, signature }))
+    }
+
+    pub fn sha256_hex(&self) -> String {
+        utils::hex_encode(&self.sha256)
+    }
+}
+
+/// hashes an artifact chunk by chunk as it is downloaded
+pub struct Verifier {
+    hasher: Sha256,
+    expected: Option<Expected>,
+}
+
+impl Verifier {
+    pub fn new(expected: Option<Expected>) -> Self {
+        Self {
+            hasher: Sha256::new(),
+            expected,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    pub fn finish(self) -> Result<(), IntegrityError> {
+        let actual: [u8; 32] = self.hasher.finalize().into();
+        let public_key = config::get().agent_public_key.as_deref();
+
+        let Some(expected) = self.expected else {
+            if public_key.is_some() {
+                return Err(IntegrityError::MissingSignature);
+            }
+            if !UNVERIFIED_WARNED.swap(true, Ordering::Relaxed) {
+                println!("WARNING: settings carry no java_agent_sha256, the agent is not verified");
+            }
+            return Ok(());
+        };
+
+        if actual != expected.sha256 {
+            return Err(IntegrityError::DigestMismatch {
+                expected: utils::hex_encode(&expected.sha256),
+                actual: utils::hex_encode(&actual),
+            });
+        }
+
+        let Some(public_key) = public_key else {
+            return Ok(());
+        };
+        let public_key = utils::hex_decode::<32>(public_key)
+            .map(ed25519_compact::PublicKey::new)
+            .ok_or(IntegrityError::InvalidPublicKey)?;
+        let signature = expected.signature.ok_or(IntegrityError::MissingSignature)?;
+
+        public_key
+            .verify(actual, &ed25519_compact::Signature::new(signature))
+            .map_err(|_| IntegrityError::BadSignature)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum IntegrityError {
+    #[error("sha256 mismatch, expected {expected} but got {actual}")]
+    DigestMismatch { expected: String, actual: String },
+
+    #[error("artifact is not signed but RUBICON_AGENT_PUBLIC_KEY requires a signature")]
+    MissingSignature,
+
+    #[error("signature does not match RUBICON_AGENT_PUBLIC_KEY")]
+    BadSignature,
+
+    #[error("RUBICON_AGENT_PUBLIC_KEY is not a hex encoded ed25519 public key")]
+    InvalidPublicKey,
+}
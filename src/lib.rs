@@ -1,4 +1,4 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(core_intrinsics)]
 #![feature(naked_functions)]
 #![feature(linkage)]
@@ -9,39 +9,52 @@ extern crate alloc;
 use core::{ffi::c_void, sync::atomic::AtomicPtr};
 
 use alloc::{
-    borrow::ToOwned, boxed::Box, collections::btree_map::BTreeMap, ffi::CString, string::String, vec::Vec,
+    borrow::ToOwned, boxed::Box, ffi::CString, string::{String, ToString}, vec::Vec,
 };
 use bstr::ByteSlice;
-use http::download_settings;
 use rustix::{
     cstr,
-    fd::{AsRawFd, IntoRawFd, OwnedFd, RawFd},
-    fs::{Mode, OFlags},
+    fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
     io::fcntl_setfd,
     pipe::{fcntl_setpipe_size, PipeFlags},
     process::WaitOptions,
     runtime::Fork,
     thread::Pid,
 };
-use rustix_dlmalloc::GlobalDlmalloc;
+use health::CrashVerdict;
+use http::HttpError;
+use integrity::Expected;
+use report::{Outcome, StatusReport};
 use settings::RemoteSettings;
-use utils::{sleep_nsecs, Argv, NANOSECONDS_PER_SECOND};
+use sources::Sources;
+use utils::{sleep_nsecs, spinlock::Mutex, Argv, NANOSECONDS_PER_SECOND};
 mod utils;
 use utils::envp::{Envp, EnvpRef};
 
+pub mod config;
 pub mod dns;
 pub mod examples;
+mod artifacts;
+mod health;
 mod http;
+mod integrity;
+pub mod report;
+pub mod runtime;
 pub mod settings;
+pub mod sources;
+pub mod targeting;
 
 // #[panic_handler]
 // fn panic(_panic: &core::panic::PanicInfo<'_>) -> ! {
 //     core::intrinsics::abort()
 // }
 
+#[cfg(not(test))]
 #[global_allocator]
-static DLMALLOC: GlobalDlmalloc = GlobalDlmalloc;
+static DLMALLOC: rustix_dlmalloc::GlobalDlmalloc = rustix_dlmalloc::GlobalDlmalloc;
 
+// the test harness is an ordinary program, it must not be taken over
+#[cfg(not(test))]
 #[used]
 #[cfg_attr(
     any(target_os = "linux", target_os = "android"),
@@ -79,117 +92,330 @@ impl Background {
     }
 }
 
-fn env_loop(child_env: ChildEnv) {
-    rustix::thread::set_name(cstr!("new_env_watcher")).unwrap();
+fn settings_poll_loop() {
+    rustix::thread::set_name(cstr!("settings_poller")).unwrap();
 
-    let file_path = CString::new(".new_env").unwrap();
+    let mut sources = Sources::from_config();
 
     loop {
-        let res = rustix::fs::stat(&file_path);
-
-        if let Ok(stat) = res {
-            let fd = rustix::fs::open(&file_path, OFlags::RDONLY, Mode::RUSR).unwrap();
-            let mut buf = [0u8; 2024];
-            let read_bytes = rustix::io::read(fd, &mut buf).unwrap();
-            let s = core::str::from_utf8(&buf[..read_bytes]).unwrap();
-
-            // Copilot: parse str as key=val
-            let mut env = BTreeMap::new();
-            for line in s.lines() {
-                let mut parts = line.split('=');
-                let key = parts.next().unwrap();
-                let val = parts.next().unwrap();
-                env.insert(key.to_owned(), val.to_owned());
+        sources.poll();
+
+        sleep_nsecs(NANOSECONDS_PER_SECOND * 2);
+    }
+}
+
+fn remote_env_loop(child_env: ChildEnv, running: ChildEnv) {
+    rustix::thread::set_name(cstr!("remote_env_watcher")).unwrap();
+
+    let mut running = running;
+    let mut agent_retry_nsecs = AGENT_RETRY_MIN_NSECS;
+    let mut pending_gen = 0;
+
+    loop {
+        let applied_gen = APPLIED_SETTINGS_GEN.load(core::sync::atomic::Ordering::SeqCst);
+        RemoteSettings::wait_for_new_generation(applied_gen, None);
+
+        let settings = RemoteSettings::snapshot().unwrap_or_default();
+        let new_gen = settings.generation();
+        APPLIED_SETTINGS_GEN.store(new_gen, core::sync::atomic::Ordering::SeqCst);
+
+        let ce = match child_env_with_settings(&child_env, &settings, Some(&running)) {
+            Ok(ce) => {
+                agent_retry_nsecs = AGENT_RETRY_MIN_NSECS;
+                ce
+            }
+            Err(err @ (HttpError::Integrity(_) | HttpError::InvalidSettings(_))) => {
+                // the agent of this generation can't be trusted, the child keeps its current one
+                let reason = format!("java agent rejected: {}", err);
+                RemoteSettings::rollback(new_gen, &reason);
+                StatusReport::new(Some(new_gen), Outcome::Rejected)
+                    .with_reason(reason)
+                    .send();
+                continue;
             }
-            rustix::fs::unlink(&file_path).unwrap();
+            Err(err) => {
+                // most likely transient, the child keeps running as it is and the
+                // generation is applied again once the agent can be downloaded
+                println!(
+                    "failed to download java agent for generation {}, retrying in {}s: {}",
+                    new_gen,
+                    agent_retry_nsecs / NANOSECONDS_PER_SECOND,
+                    err
+                );
+                APPLIED_SETTINGS_GEN.store(applied_gen, core::sync::atomic::Ordering::SeqCst);
+                sleep_nsecs(agent_retry_nsecs);
+                agent_retry_nsecs = (agent_retry_nsecs * 2).min(AGENT_RETRY_MAX_NSECS);
+                continue;
+            }
+        };
+
+        let changes = running.diff(&ce);
+        for change in changes.iter() {
+            println!("generation {} changes child config: {}", new_gen, change);
+        }
 
+        let pid = CHILD_PID.load(core::sync::atomic::Ordering::SeqCst);
+        if pid == 0 && CHILD_NEEDED.swap(false, core::sync::atomic::Ordering::SeqCst) {
+            // the child crashed its generation into a rollback, this is the one to go on with
+            println!("starting child with generation {}", new_gen);
+            child_thread(ce.clone());
+            running = replace_running(running, ce);
+        } else if changes.is_empty() {
+            println!("generation {} does not change child config, not restarting", new_gen);
+            StatusReport::new(Some(new_gen), Outcome::Unchanged).with_child_pid(pid).send();
+        } else if pid == 0 {
+            // the child is between two runs, the generation is applied once it is up again
+            if pending_gen != new_gen {
+                StatusReport::new(Some(new_gen), Outcome::Pending).send();
+                pending_gen = new_gen;
+            }
+            discard(ce, &running);
+            APPLIED_SETTINGS_GEN.store(applied_gen, core::sync::atomic::Ordering::SeqCst);
+            sleep_nsecs(PENDING_RETRY_NSECS);
+        } else {
             CHILD_RESTARTING.store(true, core::sync::atomic::Ordering::SeqCst);
+            println!("killing pid: {:?}\n", pid);
+            // a child that exited meanwhile is simply replaced, its pid must not be signalled
+            let current = CHILD_PID.load(core::sync::atomic::Ordering::SeqCst);
+            if let Some(pid) = Pid::from_raw(pid).filter(|_| current == pid) {
+                let res = rustix::process::kill_process(pid, rustix::process::Signal::Term);
+                println!("kill returned {:?}\n", res);
+            }
+
+            print!("starting new child thread\n");
+            child_thread(ce.clone());
+            running = replace_running(running, ce);
+        }
+    }
+}
+
+/// closes what only the config of the previous child needed
+fn replace_running(running: ChildEnv, ce: ChildEnv) -> ChildEnv {
+    discard(running, &ce);
+    ce
+}
 
-            let pid = CHILD_PID.load(core::sync::atomic::Ordering::SeqCst);
-            print!("killing pid: {:?}\n", pid);
-            let pid = Pid::from_raw(pid).unwrap();
-            let res = rustix::process::kill_process(pid, rustix::process::Signal::Term);
-            print!("kill returned {:?}\n", res);
+/// closes the fds of `ce` that `kept` does not hold on to
+fn discard(ce: ChildEnv, kept: &ChildEnv) {
+    for fd in ce.fds_to_drop_in_parent.iter() {
+        if !kept.fds_to_drop_in_parent.contains(fd) {
+            drop(unsafe { OwnedFd::from_raw_fd(*fd) });
+        }
+    }
+}
 
-            let mut ce: ChildEnv = child_env.clone();
+/// `running` is the config of the current child, its java agent is reused when the url
+/// and digest did not change, fails when the agent of `settings` can't be downloaded, its
+/// digest is malformed or it does not pass verification
+fn child_env_with_settings(
+    child_env: &ChildEnv,
+    settings: &RemoteSettings,
+    running: Option<&ChildEnv>,
+) -> Result<ChildEnv, HttpError> {
+    let mut ce: ChildEnv = child_env.clone();
+    ce.generation = settings.generation();
+
+    for (k, v) in settings.env.iter() {
+        ce.env.insert(k, v);
+    }
 
-            for (k, v) in env.iter() {
-                ce.env.insert(k, v);
+    if let Some(url) = &settings.java_agent_url {
+        let reused = running
+            .and_then(|running| running.java_agent.as_ref())
+            .filter(|agent| &agent.url == url && agent.sha256 == settings.java_agent_sha256)
+            .map(|agent| agent.fd);
+
+        let raw_fd = match reused {
+            Some(fd) => fd,
+            None => {
+                let expected =
+                    Expected::java_agent(settings).map_err(HttpError::InvalidSettings)?;
+                artifacts::java_agent(url, expected)?.into_raw_fd()
             }
-            print!("starting new child thread\n");
-            child_thread(ce);
         };
 
-        sleep_nsecs(NANOSECONDS_PER_SECOND / 2);
+        let java_opts = format!("-javaagent:/proc/self/fd/{}", raw_fd);
+        ce.env.insert(
+            "JAVA_AGENT_FD",
+            format!("/proc/self/fd/{}", raw_fd),
+        );
+        ce.env.insert("JAVA_TOOL_OPTIONS", java_opts);
+        ce.fds_to_drop_in_parent.push(raw_fd);
+        ce.java_agent = Some(JavaAgent {
+            url: url.clone(),
+            sha256: settings.java_agent_sha256.clone(),
+            fd: raw_fd,
+        });
     }
+
+    Ok(ce)
 }
 
-fn remote_env_loop(child_env: ChildEnv) {
-    rustix::thread::set_name(cstr!("remote_env_watcher")).unwrap();
+static BOOTSTRAP_DONE: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
 
-    loop {
-        let old_gen = RemoteSettings::get_generation();
-        let res = download_settings();
+/// fetches settings once before the first child is started, giving up after `timeout_nsecs`
+/// so an unreachable endpoint only delays the application start by a bounded amount
+fn bootstrap_settings(timeout_nsecs: u64) {
+    let thread = unsafe {
+        origin::thread::create(
+            |_args| {
+                rustix::thread::set_name(cstr!("settings_bootstrap")).unwrap();
+                Sources::from_config().poll();
+                BOOTSTRAP_DONE.store(true, core::sync::atomic::Ordering::SeqCst);
+                None
+            },
+            &[None],
+            origin::thread::default_stack_size(),
+            origin::thread::default_guard_size(),
+        )
+    };
+
+    if let Err(err) = thread {
+        println!("failed to start settings bootstrap: {:?}", err);
+        return;
+    }
 
-        if let Err(err) = res {
-            println!("Error downloading settings: {:?}", err);
+    let step = NANOSECONDS_PER_SECOND / 100;
+    let mut waited = 0;
+    while !BOOTSTRAP_DONE.load(core::sync::atomic::Ordering::SeqCst) {
+        if waited >= timeout_nsecs {
+            println!("settings bootstrap timed out, starting with current settings");
+            return;
         }
+        sleep_nsecs(step);
+        waited += step;
+    }
+}
+
+/// the config of the first child with the java agent of `settings`, as long as the agent is there
+/// by `deadline`. a slow download does not hold up the application, it starts with the env of
+/// `settings` alone and the remote loop applies the whole generation once the agent is in
+fn first_child_env(child_env: &ChildEnv, settings: RemoteSettings, deadline: u64) -> ChildEnv {
+    let generation = settings.generation();
+    let mut without_agent = settings.clone();
+    without_agent.java_agent_url = None;
+
+    let res = match settings.java_agent_url {
+        Some(_) => bootstrap_agent(child_env, settings, deadline),
+        None => Some(child_env_with_settings(child_env, &settings, None)),
+    };
+    let (ce, applied_gen) = match res {
+        Some(Ok(ce)) => (ce, generation),
+        Some(Err(err @ (HttpError::Integrity(_) | HttpError::InvalidSettings(_)))) => {
+            // the remote loop picks up whatever generation the rollback goes back to
+            let reason = format!("java agent rejected: {}", err);
+            RemoteSettings::rollback(generation, &reason);
+            StatusReport::new(Some(generation), Outcome::Rejected)
+                .with_reason(reason)
+                .send();
+            (child_env.clone(), generation)
+        }
+        Some(Err(err)) => {
+            // don't hold up the application, the remote loop retries the generation
+            println!("failed to download java agent, starting without settings: {}", err);
+            (child_env.clone(), 0)
+        }
+        None => {
+            println!("java agent not downloaded in time, starting without it");
+            let ce = child_env_with_settings(child_env, &without_agent, None)
+                .unwrap_or_else(|_| child_env.clone());
+            (ce, 0)
+        }
+    };
 
-        let new_gen = RemoteSettings::get_generation();
+    APPLIED_SETTINGS_GEN.store(applied_gen, core::sync::atomic::Ordering::SeqCst);
+    ce
+}
+
+/// what the agent download of the first child came to, and whether anyone still waits for it
+struct AgentBootstrap {
+    res: Option<Result<ChildEnv, HttpError>>,
+    abandoned: bool,
+}
 
-        if old_gen != new_gen {
-            let settings = RemoteSettings::get().unwrap_or_default();
-            let mut ce: ChildEnv = child_env.clone();
+static AGENT_BOOTSTRAP: Mutex<AgentBootstrap> = Mutex::new(AgentBootstrap {
+    res: None,
+    abandoned: false,
+});
 
-            for (k, v) in settings.env.iter() {
-                ce.env.insert(k, v);
-            }
+/// `child_env_with_settings` on a thread of its own, `None` when it is not done by `deadline`
+fn bootstrap_agent(
+    child_env: &ChildEnv,
+    settings: RemoteSettings,
+    deadline: u64,
+) -> Option<Result<ChildEnv, HttpError>> {
+    let args = Box::new((child_env.clone(), settings));
+    let args = core::ptr::NonNull::from(Box::leak(args)).cast::<c_void>();
 
-            if let Some(url) = &settings.java_agent_url {
-                let res = http::download_java(&url);
-                if let Ok(Some(fd)) = res {
-                    let raw_fd = fd.into_raw_fd();
-                    let java_opts = format!("-javaagent:/proc/self/fd/{}", raw_fd);
-                    ce.env.insert(
-                        "JAVA_AGENT_FD",
-                        format!("/proc/self/fd/{}", raw_fd),
-                    );
-                    ce.env.insert("JAVA_TOOL_OPTIONS", java_opts);
-                    ce.fds_to_drop_in_parent.push(raw_fd);
+    let thread = unsafe {
+        origin::thread::create(
+            |args| {
+                rustix::thread::set_name(cstr!("agent_bootstrap")).unwrap();
+                let args = args[0].unwrap().as_ptr() as *mut (ChildEnv, RemoteSettings);
+                let (child_env, settings) = *unsafe { Box::from_raw(args) };
+                let res = child_env_with_settings(&child_env, &settings, None);
+
+                let mut bootstrap = AGENT_BOOTSTRAP.lock();
+                match (bootstrap.abandoned, res) {
+                    // nobody is going to start a child with it
+                    (true, Ok(ce)) => {
+                        if let Some(agent) = ce.java_agent {
+                            drop(unsafe { OwnedFd::from_raw_fd(agent.fd) });
+                        }
+                    }
+                    (true, Err(_)) => {}
+                    (false, res) => bootstrap.res = Some(res),
                 }
-            }
-            let pid = CHILD_PID.load(core::sync::atomic::Ordering::SeqCst);
-            if pid != 0 {
-                CHILD_RESTARTING.store(true, core::sync::atomic::Ordering::SeqCst);
-                let pid = CHILD_PID.load(core::sync::atomic::Ordering::SeqCst);
-                println!("killing pid: {:?}\n", pid);
-                let pid = Pid::from_raw(pid).unwrap();
-                let res = rustix::process::kill_process(pid, rustix::process::Signal::Term);
-                println!("kill returned {:?}\n", res);
-    
-                print!("starting new child thread\n");
-                child_thread(ce);
-            }
-        };
+                None
+            },
+            &[Some(args)],
+            origin::thread::default_stack_size(),
+            origin::thread::default_guard_size(),
+        )
+    };
+    if let Err(err) = thread {
+        println!("failed to start agent bootstrap: {:?}", err);
+        drop(unsafe { Box::from_raw(args.as_ptr() as *mut (ChildEnv, RemoteSettings)) });
+        return None;
+    }
 
-        sleep_nsecs(NANOSECONDS_PER_SECOND * 2);
+    let step = NANOSECONDS_PER_SECOND / 100;
+    loop {
+        let mut bootstrap = AGENT_BOOTSTRAP.lock();
+        if let Some(res) = bootstrap.res.take() {
+            return Some(res);
+        }
+        if utils::monotonic_nsecs() >= deadline {
+            bootstrap.abandoned = true;
+            return None;
+        }
+        drop(bootstrap);
+        sleep_nsecs(step);
     }
 }
 
-static ARGV: AtomicPtr<*mut u8> = AtomicPtr::new(core::ptr::null_mut());
+static APPLIED_SETTINGS_GEN: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
 
-fn new_env_loop(child_env: &ChildEnv) -> Background {
-    let ce = child_env.clone().leak_non_null();
+/// how long the first child waits for the java agent of persisted settings when there is no
+/// `RUBICON_BOOTSTRAP_TIMEOUT_MS` to go by
+const AGENT_BOOTSTRAP_NSECS: u64 = 5 * NANOSECONDS_PER_SECOND;
 
+/// backoff between attempts to apply a generation whose java agent could not be downloaded
+const AGENT_RETRY_MIN_NSECS: u64 = 5 * NANOSECONDS_PER_SECOND;
+const AGENT_RETRY_MAX_NSECS: u64 = 300 * NANOSECONDS_PER_SECOND;
+/// how soon a generation is applied again while the child is between two runs
+const PENDING_RETRY_NSECS: u64 = NANOSECONDS_PER_SECOND;
+
+static ARGV: AtomicPtr<*mut u8> = AtomicPtr::new(core::ptr::null_mut());
+
+fn new_settings_poll_loop() -> Background {
     let thread = unsafe {
         origin::thread::create(
             |_args| {
-                let ce = _args[0].unwrap();
-                let child_env = ChildEnv::from_non_null(ce);
-                env_loop(child_env);
+                settings_poll_loop();
                 None
             },
-            &[Some(ce)],
+            &[None],
             origin::thread::default_stack_size(),
             origin::thread::default_guard_size(),
         )
@@ -199,18 +425,20 @@ fn new_env_loop(child_env: &ChildEnv) -> Background {
     Background { thread }
 }
 
-fn new_remote_env_loop(child_env: &ChildEnv) -> Background {
+fn new_remote_env_loop(child_env: &ChildEnv, running: &ChildEnv) -> Background {
     let ce = child_env.clone().leak_non_null();
+    let running = running.clone().leak_non_null();
 
     let thread = unsafe {
         origin::thread::create(
             |_args| {
                 let ce = _args[0].unwrap();
                 let child_env = ChildEnv::from_non_null(ce);
-                remote_env_loop(child_env);
+                let running = ChildEnv::from_non_null(_args[1].unwrap());
+                remote_env_loop(child_env, running);
                 None
             },
-            &[Some(ce)],
+            &[Some(ce), Some(running)],
             origin::thread::default_stack_size(),
             origin::thread::default_guard_size(),
         )
@@ -220,9 +448,19 @@ fn new_remote_env_loop(child_env: &ChildEnv) -> Background {
     Background { thread }
 }
 
+/// pid of the running child, 0 once it was reaped and before the first one is started
 static CHILD_PID: core::sync::atomic::AtomicI32 = core::sync::atomic::AtomicI32::new(0);
 static CHILD_RESTARTING: core::sync::atomic::AtomicBool =
     core::sync::atomic::AtomicBool::new(false);
+/// no child is running and the remote loop has to start one with the generation it applies next
+static CHILD_NEEDED: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+#[derive(Clone)]
+struct JavaAgent {
+    url: String,
+    sha256: Option<String>,
+    fd: RawFd,
+}
 
 #[derive(Clone)]
 struct ChildEnv {
@@ -230,9 +468,37 @@ struct ChildEnv {
     argv: Argv,
     path: CString,
     fds_to_drop_in_parent: Vec<RawFd>,
+    java_agent: Option<JavaAgent>,
+    /// settings generation this config was built from, 0 when running without settings
+    generation: u64,
 }
 
 impl ChildEnv {
+    /// describes everything the child could observe that differs between `self` and `other`
+    fn diff(&self, other: &ChildEnv) -> Vec<String> {
+        let mut changes: Vec<String> = self
+            .env
+            .diff(&other.env)
+            .iter()
+            .map(|change| change.to_string())
+            .collect();
+
+        if self.argv != other.argv {
+            changes.push("argv changed".to_owned());
+        }
+        if self.path != other.path {
+            changes.push(format!("path changed from {:?} to {:?}", self.path, other.path));
+        }
+        if self.fds_to_drop_in_parent != other.fds_to_drop_in_parent {
+            changes.push(format!(
+                "inherited fds changed from {:?} to {:?}",
+                self.fds_to_drop_in_parent, other.fds_to_drop_in_parent
+            ));
+        }
+
+        changes
+    }
+
     fn leak_non_null(self) -> core::ptr::NonNull<c_void> {
         let some: Box<ChildEnv> = Box::new(self);
         let data = Box::<ChildEnv>::leak(some);
@@ -275,11 +541,23 @@ fn child_thread_core(child_env: &ChildEnv) {
             CHILD_RESTARTING.store(false, core::sync::atomic::Ordering::SeqCst);
 
             print!("child pid: {:?}\n", pid);
+            StatusReport::new(Some(child_env.generation), Outcome::Started)
+                .with_child_pid(pid.as_raw_nonzero().get())
+                .send();
+            let started_at = utils::monotonic_nsecs();
             let waitopts = WaitOptions::empty();
             let res = rustix::process::waitpid(Some(pid), waitopts);
+            let ran_nsecs = utils::monotonic_nsecs() - started_at;
+            // nobody may signal a pid that could be reused from now on
+            let _ = CHILD_PID.compare_exchange(
+                pid.as_raw_nonzero().get(),
+                0,
+                core::sync::atomic::Ordering::SeqCst,
+                core::sync::atomic::Ordering::SeqCst,
+            );
             let mut child_restarting = CHILD_RESTARTING.load(core::sync::atomic::Ordering::SeqCst);
             let current_child_pid = CHILD_PID.load(core::sync::atomic::Ordering::SeqCst);
-            if current_child_pid != pid.as_raw_nonzero().get() {
+            if current_child_pid != 0 && current_child_pid != pid.as_raw_nonzero().get() {
                 print!(
                     "child pid changed from {:?} to {:?}\n",
                     pid, current_child_pid
@@ -293,13 +571,71 @@ fn child_thread_core(child_env: &ChildEnv) {
             );
 
             if !child_restarting {
-                println!("child naturallly exiting");
-                rustix::runtime::exit_group(0);
+                let status = match res {
+                    Ok(Some(status)) => Some(status),
+                    _ => None,
+                };
+                let crashed = status.is_some_and(|status| {
+                    is_crash(status, ran_nsecs, config::get().crash_grace_nsecs)
+                });
+
+                // only settings we applied can be blamed, a baseline crash is the app's own business
+                if crashed && child_env.generation != 0 {
+                    match health::record_crash(child_env.generation) {
+                        CrashVerdict::Restart => {
+                            println!(
+                                "child crashed with settings generation {}, restarting",
+                                child_env.generation
+                            );
+                            child_thread(child_env.clone());
+                        }
+                        CrashVerdict::Rollback => {
+                            // the remote loop starts the child again with what we roll back to
+                            CHILD_NEEDED.store(true, core::sync::atomic::Ordering::SeqCst);
+                            let reason = "child crashed repeatedly";
+                            RemoteSettings::rollback(child_env.generation, reason);
+                            StatusReport::new(Some(child_env.generation), Outcome::RolledBack)
+                                .with_reason(reason)
+                                .send();
+                        }
+                    }
+                    return;
+                }
+
+                let code = status.map_or(1, exit_code);
+                println!("child naturallly exiting with {}", code);
+                rustix::runtime::exit_group(code);
             }
         }
     }
 }
 
+/// an app may well exit non-zero on its own, only doing so right away is blamed on its config.
+/// of the signals only faults are crashes, a child that was terminated or killed exits as before
+fn is_crash(status: rustix::process::WaitStatus, ran_nsecs: u64, grace_nsecs: u64) -> bool {
+    use rustix::process::Signal;
+
+    let fault = status
+        .terminating_signal()
+        .and_then(|signal| Signal::from_raw(signal as i32))
+        .is_some_and(|signal| {
+            matches!(
+                signal,
+                Signal::Segv | Signal::Bus | Signal::Ill | Signal::Fpe | Signal::Abort
+            )
+        });
+    fault || (status.exit_status().is_some_and(|code| code != 0) && ran_nsecs < grace_nsecs)
+}
+
+/// what a shell would report for `status`, 128 + the signal for a child that was killed
+fn exit_code(status: rustix::process::WaitStatus) -> i32 {
+    match (status.exit_status(), status.terminating_signal()) {
+        (Some(code), _) => code as i32,
+        (None, Some(signal)) => 128 + signal as i32,
+        (None, None) => 1,
+    }
+}
+
 fn child_thread(child_env: ChildEnv) -> Background {
     let ptr = child_env.leak_non_null();
 
@@ -339,11 +675,29 @@ fn origin_main(_argc: usize, argv: *mut *mut u8, envp: *mut *mut u8) -> i32 {
 
     let path = CString::new("/proc/self/exe").unwrap();
 
-    let child_env = ChildEnv { env, argv, path, fds_to_drop_in_parent: vec![] };
+    config::Config::from_env(&env).init();
+    report::start();
+
+    let child_env = ChildEnv { env, argv, path, fds_to_drop_in_parent: vec![], java_agent: None, generation: 0 };
+
+    // start the first child with the freshest settings we can get, the remote loop only
+    // restarts it once a different generation is downloaded
+    RemoteSettings::load_persisted();
+    let bootstrap_timeout = config::get().bootstrap_timeout_nsecs;
+    let bootstrap_deadline =
+        utils::monotonic_nsecs() + bootstrap_timeout.unwrap_or(AGENT_BOOTSTRAP_NSECS);
+    if let Some(timeout) = bootstrap_timeout {
+        bootstrap_settings(timeout);
+    }
+
+    let first_child_env = match RemoteSettings::get() {
+        Some(settings) => first_child_env(&child_env, settings, bootstrap_deadline),
+        None => child_env.clone(),
+    };
 
-    new_env_loop(&child_env);
-    let l = new_remote_env_loop(&child_env);
-    child_thread(child_env);
+    let l = new_remote_env_loop(&child_env, &first_child_env);
+    new_settings_poll_loop();
+    child_thread(first_child_env);
 
     l.join();
     drop(pipe);
@@ -361,3 +715,61 @@ pub extern "C" fn do_init(_argc: i32, argv: *mut *mut u8, envp: *mut *mut u8) {
 
     unsafe { origin::program::start(mem as _) };
 }
+
+#[cfg(test)]
+mod tests {
+    use rustix::process::WaitStatus;
+
+    use super::*;
+
+    const GRACE: u64 = 10 * NANOSECONDS_PER_SECOND;
+
+    fn exited(code: u32) -> WaitStatus {
+        WaitStatus::new(code << 8)
+    }
+
+    fn killed(signal: u32) -> WaitStatus {
+        WaitStatus::new(signal)
+    }
+
+    #[test]
+    fn fault_signals_always_crash() {
+        assert!(is_crash(killed(11), 0, GRACE));
+        assert!(is_crash(killed(6), GRACE * 100, GRACE));
+        assert!(is_crash(killed(7), GRACE * 100, GRACE));
+    }
+
+    #[test]
+    fn terminating_signals_never_crash() {
+        assert!(!is_crash(killed(15), 0, GRACE));
+        assert!(!is_crash(killed(2), 0, GRACE));
+        assert!(!is_crash(killed(9), GRACE * 100, GRACE));
+    }
+
+    #[test]
+    fn failing_exits_crash_only_within_grace() {
+        assert!(is_crash(exited(1), GRACE - 1, GRACE));
+        assert!(!is_crash(exited(1), GRACE, GRACE));
+        assert!(!is_crash(exited(0), 0, GRACE));
+    }
+
+    #[test]
+    fn env_diff_leaves_out_values() {
+        let mut old = Envp::new();
+        old.insert("TOKEN", "old-secret");
+        old.insert("GONE", "gone-secret");
+        let mut new = Envp::new();
+        new.insert("TOKEN", "new-secret");
+        new.insert("ADDED", "added-secret");
+
+        let changes: Vec<String> = old.diff(&new).iter().map(|c| c.to_string()).collect();
+        assert_eq!(changes, ["GONE removed", "TOKEN changed", "ADDED added"]);
+    }
+
+    #[test]
+    fn exit_codes_follow_the_shell() {
+        assert_eq!(exit_code(exited(0)), 0);
+        assert_eq!(exit_code(exited(3)), 3);
+        assert_eq!(exit_code(killed(15)), 143);
+    }
+}
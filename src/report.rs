@@ -0,0 +1,164 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use rustix::{cstr, thread::futex};
+use serde::Serialize;
+
+use crate::{config, http, println, runtime::Runtime, sources, utils::spinlock::Mutex};
+
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// a child was started with the generation
+    Started,
+    /// the generation did not change anything the child can observe
+    Unchanged,
+    /// the generation was accepted, but there is no child to restart yet
+    Pending,
+    /// the document was refused and the current generation keeps running
+    Rejected,
+    /// the generation made the child crash and was reverted
+    RolledBack,
+}
+
+/// what the config server learns about every apply attempt
+#[derive(Serialize, Debug)]
+pub struct StatusReport {
+    pub generation: Option<u64>,
+    /// generations of the remote documents merged into `generation`
+    pub remote_generations: Vec<u64>,
+    pub applied_at: u64,
+    pub child_pid: Option<i32>,
+    pub outcome: Outcome,
+    pub rejection_reason: Option<String>,
+    pub rubicon_version: &'static str,
+    pub runtime: Runtime,
+    pub hostname: String,
+    pub service: Option<String>,
+}
+
+impl StatusReport {
+    pub fn new(generation: Option<u64>, outcome: Outcome) -> Self {
+        let identity = &config::get().identity;
+        let now = rustix::time::clock_gettime(rustix::time::ClockId::Realtime);
+
+        Self {
+            generation,
+            remote_generations: sources::remote_generations(),
+            applied_at: now.tv_sec as u64,
+            child_pid: None,
+            outcome,
+            rejection_reason: None,
+            rubicon_version: env!("CARGO_PKG_VERSION"),
+            runtime: identity.runtime,
+            hostname: identity.hostname.clone(),
+            service: identity.service.clone(),
+        }
+    }
+
+    pub fn with_child_pid(mut self, pid: i32) -> Self {
+        self.child_pid = Some(pid);
+        self
+    }
+
+    pub fn with_reason<R: Into<String>>(mut self, reason: R) -> Self {
+        self.rejection_reason = Some(reason.into());
+        self
+    }
+
+    /// queues the report for the reporter thread, never blocks the caller on the network
+    pub fn send(self) {
+        if config::get().status_url.is_none() {
+            return;
+        }
+
+        if push_bounded(&mut QUEUE.lock(), self, MAX_QUEUED_REPORTS) {
+            println!("status report queue is full, dropped the oldest report");
+        }
+        QUEUE_SEQ.fetch_add(1, Ordering::SeqCst);
+        let _ = futex::wake(&QUEUE_SEQ, futex::Flags::PRIVATE, 1);
+    }
+}
+
+/// while the status endpoint is unreachable only the latest reports are kept
+const MAX_QUEUED_REPORTS: usize = 64;
+
+static QUEUE: Mutex<VecDeque<StatusReport>> = Mutex::new(VecDeque::new());
+static QUEUE_SEQ: AtomicU32 = AtomicU32::new(0);
+
+/// pushes `item`, dropping the oldest one when `queue` already holds `cap`, true if it did
+fn push_bounded<T>(queue: &mut VecDeque<T>, item: T, cap: usize) -> bool {
+    let full = queue.len() >= cap;
+    if full {
+        queue.pop_front();
+    }
+    queue.push_back(item);
+    full
+}
+
+fn report_loop(url: &str) {
+    rustix::thread::set_name(cstr!("status_reporter")).unwrap();
+
+    loop {
+        let seq = QUEUE_SEQ.load(Ordering::SeqCst);
+        let reports = core::mem::take(&mut *QUEUE.lock());
+
+        if reports.is_empty() {
+            let _ = futex::wait(&QUEUE_SEQ, futex::Flags::PRIVATE, seq, None);
+            continue;
+        }
+
+        for report in reports {
+            let body = match serde_json::to_vec(&report) {
+                Ok(body) => body,
+                Err(err) => {
+                    println!("failed to serialize status report: {:?}", err);
+                    continue;
+                }
+            };
+            if let Err(err) = http::post_json(url, body) {
+                println!("failed to send status report: {:?}", err);
+            }
+        }
+    }
+}
+
+/// starts the reporter thread when a status endpoint is configured
+pub fn start() {
+    if config::get().status_url.is_none() {
+        return;
+    }
+
+    let thread = unsafe {
+        origin::thread::create(
+            |_args| {
+                if let Some(url) = &config::get().status_url {
+                    report_loop(url);
+                }
+                None
+            },
+            &[None],
+            origin::thread::default_stack_size(),
+            origin::thread::default_guard_size(),
+        )
+    };
+
+    if let Err(err) = thread {
+        println!("failed to start status reporter: {:?}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_queue_drops_the_oldest() {
+        let mut queue = VecDeque::new();
+        for i in 0..3 {
+            assert!(!push_bounded(&mut queue, i, 3));
+        }
+        assert!(push_bounded(&mut queue, 3, 3));
+        assert_eq!(queue, [1, 2, 3]);
+    }
+}
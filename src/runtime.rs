@@ -0,0 +1,50 @@
+use alloc::vec::Vec;
+use serde::Serialize;
+
+/// language runtime of the process we got preloaded into
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Runtime {
+    Python,
+    Java,
+    Node,
+    Ruby,
+    Dotnet,
+    #[default]
+    Unknown,
+}
+
+impl Runtime {
+    pub fn detect() -> Self {
+        if unsafe { !crate::Py_Version.is_null() } {
+            return Runtime::Python;
+        }
+
+        let exe = match rustix::fs::readlink("/proc/self/exe", Vec::new()) {
+            Ok(exe) => exe,
+            Err(_) => return Runtime::Unknown,
+        };
+        let exe = exe.to_string_lossy();
+        let name = exe.rsplit('/').next().unwrap_or_default();
+
+        match name {
+            "java" => Runtime::Java,
+            "node" | "nodejs" => Runtime::Node,
+            "dotnet" => Runtime::Dotnet,
+            _ if name.starts_with("python") => Runtime::Python,
+            _ if name.starts_with("ruby") => Runtime::Ruby,
+            _ => Runtime::Unknown,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Runtime::Python => "python",
+            Runtime::Java => "java",
+            Runtime::Node => "node",
+            Runtime::Ruby => "ruby",
+            Runtime::Dotnet => "dotnet",
+            Runtime::Unknown => "unknown",
+        }
+    }
+}
@@ -1,56 +1,396 @@
-use core::sync::atomic::{AtomicPtr, AtomicU64};
+use core::sync::atomic::{AtomicU32, AtomicU64};
 
-use alloc::{boxed::Box, collections::btree_map::BTreeMap, string::String, vec::Vec};
+use alloc::{collections::btree_map::BTreeMap, string::String, sync::Arc, vec::Vec};
+use rustix::{io::Errno, thread::futex};
 use serde::{Deserialize, Serialize};
 
-use crate::println;
+use crate::{
+    config, health,
+    integrity::Expected,
+    println,
+    targeting::Targeting,
+    utils::{self, spinlock::Mutex},
+};
+
+/// bump when the document layout changes incompatibly and add a step to `migrate`
+pub const CURRENT_SCHEMA_VERSION: u64 = 1;
+
+const MAX_ENV_ENTRIES: usize = 256;
+const MAX_ENV_KEY_LEN: usize = 256;
+const MAX_ENV_VALUE_LEN: usize = 4096;
+const MAX_URL_LEN: usize = 2048;
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
 pub struct RemoteSettings {
-    #[serde(default)]
+    schema_version: u64,
     generation: u64,
     #[serde(default)]
     pub java_agent_url: Option<String>,
+    /// hex SHA-256 of the agent jar, a download that hashes differently is never used
+    #[serde(default)]
+    pub java_agent_sha256: Option<String>,
+    /// hex ed25519 signature over the raw digest, checked against `RUBICON_AGENT_PUBLIC_KEY`
+    #[serde(default)]
+    pub java_agent_signature: Option<String>,
     #[serde(default)]
     pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub targeting: Option<Targeting>,
 }
 
 pub static SETTINGS_GEN: AtomicU64 = AtomicU64::new(0);
 
-static SETTINGS: AtomicPtr<RemoteSettings> = AtomicPtr::new(core::ptr::null_mut());
-static OLD_SETTINGS: AtomicPtr<RemoteSettings> = AtomicPtr::new(core::ptr::null_mut());
+static SETTINGS: Mutex<Option<Arc<RemoteSettings>>> = Mutex::new(None);
+
+/// bumped on every publish, subscribers futex-wait on it
+static SETTINGS_SEQ: AtomicU32 = AtomicU32::new(0);
+
+/// the last generation that was superseded without its child crashing
+static LAST_GOOD: Mutex<Option<Arc<RemoteSettings>>> = Mutex::new(None);
+static REJECTED: Mutex<Vec<u64>> = Mutex::new(Vec::new());
 
 impl RemoteSettings {
     pub fn get() -> Option<RemoteSettings> {
-        unsafe {
-            SETTINGS
-                .load(core::sync::atomic::Ordering::Relaxed)
-                .as_ref().map(|s|s.clone()) //clone ASAP to avoid contention
+        Self::snapshot().map(|s| (*s).clone())
+    }
+
+    /// the current settings, readers keep their snapshot alive independently of later stores
+    pub fn snapshot() -> Option<Arc<RemoteSettings>> {
+        SETTINGS.lock().clone()
+    }
+
+    /// blocks until a generation other than `seen` is published or `timeout_nsecs` elapses,
+    /// returns the generation current at that point
+    pub fn wait_for_new_generation(seen: u64, timeout_nsecs: Option<u64>) -> u64 {
+        loop {
+            let seq = SETTINGS_SEQ.load(core::sync::atomic::Ordering::SeqCst);
+            let current = Self::get_generation();
+            if current != seen {
+                return current;
+            }
+
+            let timeout = timeout_nsecs.map(utils::timespec_from_nsecs);
+            match futex::wait(&SETTINGS_SEQ, futex::Flags::PRIVATE, seq, timeout) {
+                Err(Errno::TIMEDOUT) => return Self::get_generation(),
+                // woken up, interrupted or seq already moved on - check again
+                _ => {}
+            }
         }
     }
-    
+
     pub fn get_generation() -> u64 {
         SETTINGS_GEN.load(core::sync::atomic::Ordering::Relaxed)
     }
 
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// parses, migrates and validates a settings document,
+    /// anything that is not fully understood is rejected instead of applied
+    pub fn parse(data: &[u8]) -> Result<RemoteSettings, SettingsError> {
+        let mut value: serde_json::Value =
+            serde_json::from_slice(data).map_err(SettingsError::Deserialization)?;
+        migrate(&mut value)?;
+
+        let settings: RemoteSettings =
+            serde_json::from_value(value).map_err(SettingsError::Deserialization)?;
+        settings.validate()?;
+
+        Ok(settings)
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), SettingsError> {
+        if self.env.len() > MAX_ENV_ENTRIES {
+            return Err(SettingsError::TooManyEnvEntries(self.env.len()));
+        }
+
+        for (key, value) in self.env.iter() {
+            validate_env_key(key)?;
+            if value.len() > MAX_ENV_VALUE_LEN || value.contains('\0') {
+                return Err(SettingsError::InvalidEnvValue(key.clone()));
+            }
+        }
+
+        if let Some(url) = &self.java_agent_url {
+            validate_url(url)?;
+        }
+        Expected::java_agent(self)?;
+
+        if let Some(targeting) = &self.targeting {
+            targeting.validate()?;
+        }
+
+        Ok(())
+    }
+
+    /// a settings layer that did not come from a document, e.g. a local file or env overrides
+    pub fn from_layer(java_agent_url: Option<String>, env: BTreeMap<String, String>) -> RemoteSettings {
+        RemoteSettings {
+            schema_version: CURRENT_SCHEMA_VERSION,
+            java_agent_url,
+            env,
+            ..Default::default()
+        }
+    }
+
+    /// merges `layers` from lowest to highest precedence into one document, its generation is
+    /// derived from the content so merging the same inputs again never looks like a change
+    pub fn layered<'a>(layers: impl IntoIterator<Item = &'a RemoteSettings>) -> RemoteSettings {
+        let mut merged = RemoteSettings::from_layer(None, BTreeMap::new());
+
+        for layer in layers {
+            // the digest and signature belong to the url they came with
+            if layer.java_agent_url.is_some() {
+                merged.java_agent_url = layer.java_agent_url.clone();
+                merged.java_agent_sha256 = layer.java_agent_sha256.clone();
+                merged.java_agent_signature = layer.java_agent_signature.clone();
+            } else if layer.java_agent_sha256.is_some() {
+                // a digest on its own pins the agent of the layers below, their signature
+                // only stays when it was made for that very digest
+                if layer.java_agent_sha256 != merged.java_agent_sha256 {
+                    merged.java_agent_signature = layer.java_agent_signature.clone();
+                }
+                merged.java_agent_sha256 = layer.java_agent_sha256.clone();
+            }
+            for (key, value) in layer.env.iter() {
+                merged.env.insert(key.clone(), value.clone());
+            }
+        }
+
+        let content = serde_json::to_vec(&(
+            &merged.java_agent_url,
+            &merged.java_agent_sha256,
+            &merged.java_agent_signature,
+            &merged.env,
+        ))
+        .unwrap_or_default();
+        // 0 is reserved for "no settings"
+        merged.generation = utils::fnv1a64(&content).max(1);
+        merged
+    }
+
+    /// whether this document is meant for this process at all
+    pub fn targets_this_process(&self) -> bool {
+        match &self.targeting {
+            Some(targeting) => targeting.matches(&config::get().identity),
+            None => true,
+        }
+    }
+
     pub fn store(self) {
-        let generation = self.generation;
+        // a document that stopped targeting us takes its settings away instead of leaving the
+        // ones it applied before in place
+        let settings = match self.targets_this_process() {
+            true => self,
+            false => {
+                println!(
+                    "settings generation {} does not target this process, dropping it",
+                    self.generation
+                );
+                RemoteSettings::default()
+            }
+        };
+
+        let generation = settings.generation;
         let old_generation = SETTINGS_GEN.load(core::sync::atomic::Ordering::Relaxed);
 
         if generation == old_generation {
             return;
         }
-        println!("new settings downloaded, storing: {:?}", self);
+        if Self::is_rejected(generation) {
+            return;
+        }
+        println!("new settings downloaded, storing: {:?}", settings);
+
+        if let Err(err) = settings.persist() {
+            println!("failed to persist settings: {:?}", err);
+        }
+
+        settings.publish();
+    }
+
+    /// loads the last accepted settings from the on-disk cache and publishes them,
+    /// so a fresh process starts from the last known config instead of generation 0
+    pub fn load_persisted() -> Option<RemoteSettings> {
+        let path = &config::get().settings_cache_path;
+        // whoever can write the file decides the environment of the child
+        let data = match utils::read_private_file(path) {
+            Ok(data) => data,
+            Err(Errno::NOENT) => return None,
+            Err(err) => {
+                println!("ignoring settings cache {}: {}", path, err);
+                return None;
+            }
+        };
+
+        let settings = match RemoteSettings::parse(&data) {
+            Ok(settings) => settings,
+            Err(err) => {
+                println!("ignoring unreadable settings cache {}: {:?}", path, err);
+                return None;
+            }
+        };
+        if !settings.targets_this_process() {
+            println!("cached settings generation {} no longer targets this process", settings.generation);
+            return None;
+        }
+        println!("loaded cached settings generation {}", settings.generation);
+
+        settings.clone().publish();
+        Some(settings)
+    }
+
+    pub fn is_rejected(generation: u64) -> bool {
+        REJECTED.lock().contains(&generation)
+    }
+
+    /// marks `generation` as bad and goes back to the last known good settings,
+    /// or to no settings at all when there is nothing to go back to
+    pub fn rollback(generation: u64, reason: &str) {
+        println!("rejecting settings generation {}: {}", generation, reason);
+        REJECTED.lock().push(generation);
+
+        let last_good = LAST_GOOD
+            .lock()
+            .clone()
+            .filter(|last_good| !Self::is_rejected(last_good.generation));
+        let settings = match last_good {
+            Some(last_good) => (*last_good).clone(),
+            None => RemoteSettings::default(),
+        };
+        println!("rolling back to settings generation {}", settings.generation);
+
+        if let Err(err) = settings.persist() {
+            println!("failed to persist rolled back settings: {:?}", err);
+        }
+
+        settings.publish();
+    }
+
+    /// no settings at all are persisted as no cache file
+    fn persist(&self) -> Result<(), SettingsError> {
+        let path = &config::get().settings_cache_path;
+        if self.generation == 0 {
+            return match rustix::fs::unlink(path.as_str()) {
+                Ok(()) | Err(Errno::NOENT) => Ok(()),
+                Err(err) => Err(SettingsError::Errno(err)),
+            };
+        }
+
+        let data: Vec<u8> = serde_json::to_vec(self).map_err(SettingsError::Serialization)?;
+        utils::ensure_private_dir(utils::parent_dir(path)).map_err(SettingsError::Errno)?;
+        utils::write_file_atomic(path, &data).map_err(SettingsError::Errno)
+    }
+
+    fn publish(self) {
+        let generation = self.generation;
+
+        let previous = {
+            let mut current = SETTINGS.lock();
+            let previous = current.replace(Arc::new(self));
+            SETTINGS_GEN.store(generation, core::sync::atomic::Ordering::SeqCst);
+            previous
+        };
+
+        if let Some(previous) = previous {
+            let previous_gen = previous.generation;
+            if previous_gen != generation
+                && !Self::is_rejected(previous_gen)
+                && !health::has_crashed(previous_gen)
+            {
+                *LAST_GOOD.lock() = Some(previous);
+            }
+        }
+
+        SETTINGS_SEQ.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
+        let _ = futex::wake(&SETTINGS_SEQ, futex::Flags::PRIVATE, i32::MAX as u32);
+    }
+}
 
+fn migrate(value: &mut serde_json::Value) -> Result<(), SettingsError> {
+    let object = value.as_object_mut().ok_or(SettingsError::NotAnObject)?;
 
-        let old = SETTINGS.swap(Box::into_raw(Box::new(self)), core::sync::atomic::Ordering::SeqCst);
-        // I don't like just leaking things - lets try ultra optimistic memory management
-        let probably_forgotten_by_now = OLD_SETTINGS.swap(old, core::sync::atomic::Ordering::SeqCst);
+    let version = match object.get("schema_version") {
+        None => 0,
+        Some(version) => version.as_u64().ok_or(SettingsError::InvalidSchemaVersion)?,
+    };
 
-        if (probably_forgotten_by_now as *const RemoteSettings) != core::ptr::null() {
-            let _free = unsafe { Box::from_raw(probably_forgotten_by_now) };
+    match version {
+        // documents from before schema_version existed, same layout as version 1
+        0 => {
+            object.insert("schema_version".into(), CURRENT_SCHEMA_VERSION.into());
         }
-        
-        SETTINGS_GEN.store(generation, core::sync::atomic::Ordering::Relaxed);
+        CURRENT_SCHEMA_VERSION => {}
+        other => return Err(SettingsError::UnsupportedSchemaVersion(other)),
     }
+
+    Ok(())
+}
+
+pub(crate) fn validate_env_key(key: &str) -> Result<(), SettingsError> {
+    let mut chars = key.chars();
+    let valid_start = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_');
+    let valid_rest = chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    if !valid_start || !valid_rest || key.len() > MAX_ENV_KEY_LEN {
+        return Err(SettingsError::InvalidEnvKey(key.into()));
+    }
+    Ok(())
+}
+
+fn validate_url(url: &str) -> Result<(), SettingsError> {
+    if url.len() > MAX_URL_LEN || !url.starts_with("https://") {
+        return Err(SettingsError::InvalidUrl(url.into()));
+    }
+    Ok(())
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SettingsError {
+    #[error("Serialization error {0}")]
+    Serialization(serde_json::Error),
+
+    #[error("Deserialization error {0}")]
+    Deserialization(serde_json::Error),
+
+    #[error("settings document is not a JSON object")]
+    NotAnObject,
+
+    #[error("schema_version is not a number")]
+    InvalidSchemaVersion,
+
+    #[error("unsupported schema_version {0}")]
+    UnsupportedSchemaVersion(u64),
+
+    #[error("too many env entries: {0}")]
+    TooManyEnvEntries(usize),
+
+    #[error("invalid env key {0:?}")]
+    InvalidEnvKey(String),
+
+    #[error("invalid value for env key {0:?}")]
+    InvalidEnvValue(String),
+
+    #[error("invalid url {0:?}")]
+    InvalidUrl(String),
+
+    #[error("rollout percentage {0} is over 100")]
+    InvalidRolloutPercentage(u8),
+
+    #[error("invalid hostname pattern {0:?}")]
+    InvalidHostnamePattern(String),
+
+    #[error("invalid sha256 digest {0:?}")]
+    InvalidDigest(String),
+
+    #[error("invalid signature {0:?}")]
+    InvalidSignature(String),
+
+    #[error("signature without a sha256 digest")]
+    SignatureWithoutDigest,
+
+    #[error("Errno {0}")]
+    Errno(Errno),
 }
@@ -0,0 +1,214 @@
+//! Layered settings: every poll merges, from lowest to highest precedence,
+//! the baked in defaults, each remote source in the order configured,
+//! the local env file and finally the `RUBICON_OVERRIDE_*` environment overrides
+//! into one `RemoteSettings` generation for the supervisor.
+
+use alloc::{collections::btree_map::BTreeMap, string::String, vec::Vec};
+
+use crate::{
+    config,
+    http::{HttpError, SettingsClient},
+    println,
+    report::{Outcome, StatusReport},
+    settings::{RemoteSettings, SettingsError},
+    utils::{self, spinlock::Mutex},
+};
+
+/// backoff after a 429 or 503 that did not say for how long
+const DEFAULT_RETRY_AFTER_SECS: u64 = 30;
+
+struct RemoteSource {
+    url: String,
+    /// created on first use and kept, it holds the connection open between polls
+    client: Option<SettingsClient>,
+    /// last accepted document, kept while the source is unreachable
+    settings: Option<RemoteSettings>,
+    /// whether the source said what it has for this process at least once
+    answered: bool,
+    last_rejection: Option<String>,
+    /// monotonic time before which the server asked not to be polled again
+    retry_at: u64,
+}
+
+pub struct Sources {
+    remote: Vec<RemoteSource>,
+}
+
+impl Sources {
+    pub fn from_config() -> Self {
+        let remote = config::get()
+            .settings_urls
+            .iter()
+            .map(|url| RemoteSource {
+                url: url.clone(),
+                client: None,
+                settings: None,
+                answered: false,
+                last_rejection: None,
+                retry_at: 0,
+            })
+            .collect();
+
+        Self { remote }
+    }
+
+    /// refreshes every layer and stores the merged result
+    pub fn poll(&mut self) {
+        for source in self.remote.iter_mut() {
+            source.poll();
+        }
+
+        let defaults = defaults();
+        let local = match local_file_layer() {
+            Ok(local) => local,
+            Err(err) => {
+                println!("ignoring local settings file: {:?}", err);
+                None
+            }
+        };
+        let mut overrides = RemoteSettings::from_layer(
+            config::get().java_agent_url_override.clone(),
+            config::get().env_overrides.clone(),
+        );
+        overrides.java_agent_sha256 = config::get().java_agent_sha256_override.clone();
+
+        // a merge without a source that never answered would replace the persisted
+        // last-known-good settings by less, those stay until every source had its say
+        let waiting: Vec<&str> = self
+            .remote
+            .iter()
+            .filter(|source| !source.answered)
+            .map(|source| source.url.as_str())
+            .collect();
+        if !waiting.is_empty() && RemoteSettings::get().is_some() {
+            println!("keeping persisted settings until {:?} answered", waiting);
+            return;
+        }
+
+        let mut layers = Vec::new();
+        layers.push(&defaults);
+        layers.extend(self.remote.iter().filter_map(|source| source.settings.as_ref()));
+        layers.extend(local.as_ref());
+        layers.push(&overrides);
+
+        let merged = RemoteSettings::layered(layers);
+        if let Err(err) = merged.validate() {
+            println!("merged settings are invalid, keeping current generation: {}", err);
+            return;
+        }
+
+        *REMOTE_GENERATIONS.lock() = self
+            .remote
+            .iter()
+            .filter_map(|source| source.settings.as_ref())
+            .map(|settings| settings.generation())
+            .collect();
+
+        merged.store();
+    }
+}
+
+static REMOTE_GENERATIONS: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+/// generations of the remote documents that went into the last merge,
+/// the merged generation itself only means something to this process
+pub fn remote_generations() -> Vec<u64> {
+    REMOTE_GENERATIONS.lock().clone()
+}
+
+impl RemoteSource {
+    fn poll(&mut self) {
+        if utils::monotonic_nsecs() < self.retry_at {
+            return;
+        }
+
+        let client = match &mut self.client {
+            Some(client) => client,
+            None => match SettingsClient::new(&self.url) {
+                Ok(client) => self.client.insert(client),
+                Err(err) => {
+                    println!("Error downloading settings from {}: {:?}", self.url, err);
+                    return;
+                }
+            },
+        };
+
+        match client.fetch() {
+            Ok(settings) => {
+                self.answered = true;
+                self.last_rejection = None;
+
+                // narrowing a rollout or a selector has to reach processes already enrolled
+                if !settings.targets_this_process() {
+                    if self.settings.take().is_some() {
+                        println!("settings from {} no longer target this process", self.url);
+                    }
+                    return;
+                }
+                self.settings = Some(settings);
+            }
+            // the document we have is still the current one
+            Err(HttpError::NotModified) => self.answered = true,
+            // an answer rather than an outage, there is nothing to keep from this source
+            Err(HttpError::NoConfig) => {
+                self.answered = true;
+                if self.settings.take().is_some() {
+                    println!("{} has no settings for this service anymore", self.url);
+                }
+            }
+            Err(HttpError::RateLimited(retry_after) | HttpError::Unavailable(retry_after)) => {
+                let secs = retry_after.unwrap_or(DEFAULT_RETRY_AFTER_SECS);
+                println!("{} asked to back off, polling it again in {}s", self.url, secs);
+                self.retry_at = utils::monotonic_nsecs() + secs * utils::NANOSECONDS_PER_SECOND;
+            }
+            Err(HttpError::InvalidSettings(err)) => {
+                println!("rejected settings from {}: {}", self.url, err);
+
+                // the same broken document comes back on every poll, report it once
+                let reason = format!("{}: {}", self.url, err);
+                if self.last_rejection.as_ref() != Some(&reason) {
+                    StatusReport::new(None, Outcome::Rejected)
+                        .with_reason(reason.clone())
+                        .send();
+                    self.last_rejection = Some(reason);
+                }
+            }
+            Err(err) => {
+                println!("Error downloading settings from {}: {:?}", self.url, err);
+            }
+        }
+    }
+}
+
+/// settings every process starts from before any other layer is applied
+fn defaults() -> RemoteSettings {
+    RemoteSettings::from_layer(None, BTreeMap::new())
+}
+
+/// `KEY=value` lines from the local env file, the file going away removes the layer
+fn local_file_layer() -> Result<Option<RemoteSettings>, SettingsError> {
+    let path = &config::get().local_env_file;
+    let data = match utils::read_file(path) {
+        Ok(data) => data,
+        Err(rustix::io::Errno::NOENT) => return Ok(None),
+        Err(err) => return Err(SettingsError::Errno(err)),
+    };
+
+    let mut env = BTreeMap::new();
+    for line in String::from_utf8_lossy(&data).lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((key, value)) => {
+                env.insert(key.into(), value.into());
+            }
+            None => return Err(SettingsError::InvalidEnvKey(line.into())),
+        }
+    }
+
+    let layer = RemoteSettings::from_layer(None, env);
+    layer.validate()?;
+    Ok(Some(layer))
+}
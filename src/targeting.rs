@@ -0,0 +1,208 @@
+use alloc::{string::String, vec::Vec};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    runtime::Runtime,
+    settings::SettingsError,
+    utils::{self, envp::Envp},
+};
+
+const MAX_ROLLOUT_PERCENTAGE: u8 = 100;
+
+/// selects which processes a settings document applies to,
+/// empty selectors match everything
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Targeting {
+    #[serde(default)]
+    pub service: Vec<String>,
+    #[serde(default)]
+    pub env: Vec<String>,
+    #[serde(default)]
+    pub version: Vec<String>,
+    #[serde(default)]
+    pub runtime: Vec<String>,
+    /// regex matched against the hostname (pod name in kubernetes)
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub rollout_percentage: Option<u8>,
+    /// changing the key picks a different set of hosts for the same percentage,
+    /// keeping it lets a rollout grow from 5% to 100% without reshuffling the canaries
+    #[serde(default)]
+    pub rollout_key: String,
+}
+
+impl Targeting {
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        if let Some(percentage) = self.rollout_percentage {
+            if percentage > MAX_ROLLOUT_PERCENTAGE {
+                return Err(SettingsError::InvalidRolloutPercentage(percentage));
+            }
+        }
+
+        if let Some(hostname) = &self.hostname {
+            regex_automata::meta::Regex::new(hostname)
+                .map_err(|_| SettingsError::InvalidHostnamePattern(hostname.clone()))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn matches(&self, identity: &Identity) -> bool {
+        fn selected(selector: &[String], value: Option<&str>) -> bool {
+            selector.is_empty() || value.is_some_and(|value| selector.iter().any(|s| s == value))
+        }
+
+        if !selected(&self.service, identity.service.as_deref())
+            || !selected(&self.env, identity.env.as_deref())
+            || !selected(&self.version, identity.version.as_deref())
+            || !selected(&self.runtime, Some(identity.runtime.as_str()))
+        {
+            return false;
+        }
+
+        if let Some(hostname) = &self.hostname {
+            match regex_automata::meta::Regex::new(hostname) {
+                Ok(re) if re.is_match(identity.hostname.as_str()) => {}
+                _ => return false,
+            }
+        }
+
+        match self.rollout_percentage {
+            Some(percentage) => {
+                rollout_bucket(&self.rollout_key, &identity.hostname) < percentage as u64
+            }
+            None => true,
+        }
+    }
+}
+
+/// stable bucket in 0..100 for this host, FNV-1a so it is the same across restarts and builds
+fn rollout_bucket(key: &str, hostname: &str) -> u64 {
+    let data = format!("{}:{}", key, hostname);
+    utils::fnv1a64(data.as_bytes()) % 100
+}
+
+/// what targeting selectors are evaluated against
+#[derive(Debug, Clone, Default)]
+pub struct Identity {
+    pub service: Option<String>,
+    pub env: Option<String>,
+    pub version: Option<String>,
+    pub hostname: String,
+    pub runtime: Runtime,
+}
+
+impl Identity {
+    pub fn detect(env: &Envp) -> Self {
+        let uname = rustix::system::uname();
+        let hostname = uname.nodename().to_string_lossy().into();
+
+        Self {
+            service: env.get_value("DD_SERVICE"),
+            env: env.get_value("DD_ENV"),
+            version: env.get_value("DD_VERSION"),
+            hostname,
+            runtime: Runtime::detect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(hostname: &str) -> Identity {
+        Identity {
+            service: Some("checkout".into()),
+            env: Some("prod".into()),
+            version: None,
+            hostname: hostname.into(),
+            runtime: Runtime::Java,
+        }
+    }
+
+    #[test]
+    fn rollout_bucket_is_stable_and_in_range() {
+        for host in ["web-0", "web-1", "checkout-7d9f8-abcde", ""] {
+            let bucket = rollout_bucket("canary", host);
+            assert!(bucket < 100);
+            assert_eq!(bucket, rollout_bucket("canary", host));
+        }
+    }
+
+    #[test]
+    fn rollout_key_reshuffles_hosts() {
+        let hosts: Vec<String> = (0..64).map(|i| format!("web-{}", i)).collect();
+        let buckets = |key| hosts.iter().map(|h| rollout_bucket(key, h)).collect::<Vec<_>>();
+        assert_ne!(buckets("a"), buckets("b"));
+    }
+
+    #[test]
+    fn rollout_grows_without_dropping_hosts() {
+        let hosts: Vec<Identity> = (0..200).map(|i| identity(&format!("web-{}", i))).collect();
+        let enrolled = |percentage| {
+            let targeting = Targeting {
+                rollout_percentage: Some(percentage),
+                ..Default::default()
+            };
+            hosts.iter().map(|host| targeting.matches(host)).collect::<Vec<_>>()
+        };
+
+        let (small, large) = (enrolled(5), enrolled(50));
+        assert!(small.iter().zip(&large).all(|(small, large)| !small || *large));
+        assert!(enrolled(0).iter().all(|matched| !matched));
+        assert!(enrolled(100).iter().all(|matched| *matched));
+    }
+
+    #[test]
+    fn selectors() {
+        let host = identity("web-0");
+        let matches = |targeting: Targeting| targeting.matches(&host);
+
+        assert!(matches(Targeting::default()));
+        assert!(matches(Targeting {
+            service: vec!["checkout".into(), "cart".into()],
+            runtime: vec!["java".into()],
+            ..Default::default()
+        }));
+        assert!(!matches(Targeting {
+            env: vec!["staging".into()],
+            ..Default::default()
+        }));
+        // a selector on something we don't know never matches
+        assert!(!matches(Targeting {
+            version: vec!["1.0".into()],
+            ..Default::default()
+        }));
+        assert!(matches(Targeting {
+            hostname: Some("^web-[0-9]+$".into()),
+            ..Default::default()
+        }));
+        assert!(!matches(Targeting {
+            hostname: Some("^db-".into()),
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn validate() {
+        let percentage = Targeting {
+            rollout_percentage: Some(101),
+            ..Default::default()
+        };
+        assert!(matches!(
+            percentage.validate(),
+            Err(SettingsError::InvalidRolloutPercentage(101))
+        ));
+        let hostname = Targeting {
+            hostname: Some("(".into()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            hostname.validate(),
+            Err(SettingsError::InvalidHostnamePattern(_))
+        ));
+    }
+}
@@ -1,8 +1,15 @@
 use core::ffi::CStr;
 
-use alloc::{borrow::ToOwned, ffi::CString, vec::Vec};
+use alloc::{borrow::ToOwned, ffi::CString, string::String, vec::Vec};
+use rustix::{
+    fd::OwnedFd,
+    fs::{FileType, Mode, OFlags, Stat},
+    io::Errno,
+    rand::GetRandomFlags,
+};
 
 pub mod envp;
+pub mod spinlock;
 
 pub fn do_print<T: AsRef<str>>(msg: T) {
     let bytes = msg.as_ref().as_bytes();
@@ -26,21 +33,213 @@ macro_rules! println {
     })
 }
 
-pub fn sleep_nsecs(nsecs: u64) {
+pub fn timespec_from_nsecs(nsecs: u64) -> rustix::fs::Timespec {
     let secs = nsecs / NANOSECONDS_PER_SECOND;
     let nsecs = nsecs % NANOSECONDS_PER_SECOND;
 
-    let request = rustix::fs::Timespec {
+    rustix::fs::Timespec {
         tv_sec: secs as i64,
         tv_nsec: nsecs as i64,
-    };
+    }
+}
+
+pub fn monotonic_nsecs() -> u64 {
+    let now = rustix::time::clock_gettime(rustix::time::ClockId::Monotonic);
+    now.tv_sec as u64 * NANOSECONDS_PER_SECOND + now.tv_nsec as u64
+}
+
+pub fn sleep_nsecs(nsecs: u64) {
+    let request = timespec_from_nsecs(nsecs);
 
     let res = rustix::thread::nanosleep(&request);
 }
 
 pub const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+pub const NANOSECONDS_PER_MILLISECOND: u64 = 1_000_000;
+
+pub fn fnv1a64(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+pub fn hex_encode(data: &[u8]) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut out = String::with_capacity(data.len() * 2);
+    for b in data {
+        out.push(DIGITS[(b >> 4) as usize] as char);
+        out.push(DIGITS[(b & 0xf) as usize] as char);
+    }
+    out
+}
+
+/// decodes exactly `N` bytes of hex, either case
+pub fn hex_decode<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let hex = hex.as_bytes();
+    if hex.len() != N * 2 || !hex.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+
+    let mut out = [0u8; N];
+    for (byte, pair) in out.iter_mut().zip(hex.chunks(2)) {
+        let digits = core::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(digits, 16).ok()?;
+    }
+    Some(out)
+}
+
+pub fn read_file(path: &str) -> Result<Vec<u8>, Errno> {
+    let fd = rustix::fs::open(path, OFlags::RDONLY | OFlags::CLOEXEC, Mode::empty())?;
+    read_all(&fd)
+}
+
+fn read_all(fd: &OwnedFd) -> Result<Vec<u8>, Errno> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let read_bytes = rustix::io::read(fd, &mut buf)?;
+        if read_bytes == 0 {
+            return Ok(data);
+        }
+        data.extend_from_slice(&buf[..read_bytes]);
+    }
+}
+
+/// write to a temporary file next to `path` and rename it into place,
+/// so readers never observe a half written file
+pub fn write_file_atomic(path: &str, data: &[u8]) -> Result<(), Errno> {
+    let (tmp_path, fd) = create_tmp_file(path)?;
+
+    let res = write_all(&fd, data).and_then(|_| rustix::fs::fsync(&fd));
+    drop(fd);
+
+    if let Err(err) = res {
+        let _ = rustix::fs::unlink(tmp_path.as_str());
+        return Err(err);
+    }
+
+    rustix::fs::rename(tmp_path.as_str(), path)
+}
+
+/// a new 0600 file next to `path` with a name nobody can guess, neither an existing file
+/// nor a symlink planted under that name is ever opened
+fn create_tmp_file(path: &str) -> Result<(String, OwnedFd), Errno> {
+    loop {
+        let tmp_path = format!("{}.tmp.{}", path, hex_encode(&random_bytes::<8>()?));
+        match rustix::fs::open(
+            tmp_path.as_str(),
+            OFlags::WRONLY | OFlags::CREATE | OFlags::EXCL | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+            Mode::RUSR | Mode::WUSR,
+        ) {
+            Ok(fd) => return Ok((tmp_path, fd)),
+            Err(Errno::EXIST) => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
 
-#[derive(Clone)]
+pub fn random_bytes<const N: usize>() -> Result<[u8; N], Errno> {
+    let mut buf = [0u8; N];
+    fill_random(&mut buf)?;
+    Ok(buf)
+}
+
+pub fn fill_random(buf: &mut [u8]) -> Result<(), Errno> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match rustix::rand::getrandom(&mut buf[filled..], GetRandomFlags::empty()) {
+            Ok(len) => filled += len,
+            Err(Errno::INTR) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// what tells a rotated file apart, kubelet and cert-manager swap a symlink to a new file
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct FileId {
+    dev: u64,
+    ino: u64,
+    size: i64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl From<&Stat> for FileId {
+    fn from(stat: &Stat) -> Self {
+        Self {
+            dev: stat.st_dev as u64,
+            ino: stat.st_ino as u64,
+            size: stat.st_size as i64,
+            mtime: stat.st_mtime as i64,
+            mtime_nsec: stat.st_mtime_nsec as i64,
+        }
+    }
+}
+
+/// whether a file or directory can be trusted with what we keep in it: it belongs to us or
+/// root and nobody else can write to it
+pub fn is_trusted(stat: &Stat) -> bool {
+    let owner = stat.st_uid;
+    let writable_by_others = stat.st_mode & 0o022 != 0;
+    (owner == rustix::process::geteuid().as_raw() || owner == 0) && !writable_by_others
+}
+
+/// creates `dir` 0700 when it is missing, an existing one has to be trusted, otherwise
+/// whoever owns it could swap the files we put there
+pub fn ensure_private_dir(dir: &str) -> Result<(), Errno> {
+    match rustix::fs::mkdir(dir, Mode::RWXU) {
+        Ok(()) | Err(Errno::EXIST) => {}
+        Err(err) => return Err(err),
+    }
+
+    let fd = rustix::fs::open(
+        dir,
+        OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+        Mode::empty(),
+    )?;
+    if !is_trusted(&rustix::fs::fstat(&fd)?) {
+        return Err(Errno::PERM);
+    }
+    Ok(())
+}
+
+/// like `read_file`, but only for a regular file that `is_trusted`, symlinks are not followed
+pub fn read_private_file(path: &str) -> Result<Vec<u8>, Errno> {
+    let fd = rustix::fs::open(
+        path,
+        OFlags::RDONLY | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+        Mode::empty(),
+    )?;
+    let stat = rustix::fs::fstat(&fd)?;
+    if FileType::from_raw_mode(stat.st_mode) != FileType::RegularFile || !is_trusted(&stat) {
+        return Err(Errno::PERM);
+    }
+    read_all(&fd)
+}
+
+/// the directory `path` is in
+pub fn parent_dir(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) => "/",
+        Some(slash) => &path[..slash],
+        None => ".",
+    }
+}
+
+pub fn write_all<Fd: rustix::fd::AsFd>(fd: Fd, mut data: &[u8]) -> Result<(), Errno> {
+    while !data.is_empty() {
+        let written = rustix::io::write(&fd, data)?;
+        data = &data[written..];
+    }
+    Ok(())
+}
+
+#[derive(Clone, PartialEq)]
 pub struct Argv {
     args: Vec<CString>,
 }
@@ -106,3 +305,24 @@ impl ParamsRef<'_> {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parent_dir_of_paths() {
+        assert_eq!(parent_dir("/run/rubicon/settings.json"), "/run/rubicon");
+        assert_eq!(parent_dir("/settings.json"), "/");
+        assert_eq!(parent_dir("settings.json"), ".");
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        let bytes = [0x00, 0x7f, 0xab, 0xff];
+        assert_eq!(hex_encode(&bytes), "007fabff");
+        assert_eq!(hex_decode::<4>("007FABff"), Some(bytes));
+        assert_eq!(hex_decode::<4>("007fab"), None);
+        assert_eq!(hex_decode::<4>("007fabzz"), None);
+    }
+}
@@ -1,6 +1,7 @@
 use core::ffi::CStr;
 
-use alloc::{borrow::ToOwned, ffi::CString, vec::Vec};
+use alloc::{borrow::ToOwned, collections::btree_map::BTreeMap, ffi::CString, vec::Vec};
+use bstr::BStr;
 
 pub struct EnvpRef<'a> {
     env: Vec<&'a CStr>,
@@ -79,6 +80,50 @@ impl Envp {
         None
     }
 
+    /// `(key, value)` pairs of every entry that is valid UTF-8
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.env
+            .iter()
+            .filter_map(|s| s.to_str().ok())
+            .filter_map(|s| s.split_once('='))
+    }
+
+    fn as_map(&self) -> BTreeMap<&BStr, &BStr> {
+        let mut map = BTreeMap::new();
+        for s in self.env.iter() {
+            let bytes = s.as_bytes();
+            let (key, value) = match bytes.iter().position(|b| *b == b'=') {
+                Some(pos) => (&bytes[..pos], &bytes[pos + 1..]),
+                None => (bytes, &b""[..]),
+            };
+            map.insert(BStr::new(key), BStr::new(value));
+        }
+        map
+    }
+
+    /// lists the variables that differ between `self` and `other`, ignoring ordering
+    pub fn diff<'a>(&'a self, other: &'a Envp) -> Vec<EnvChange<'a>> {
+        let old = self.as_map();
+        let new = other.as_map();
+
+        let mut changes = Vec::new();
+        for (&key, &old_value) in old.iter() {
+            match new.get(key) {
+                None => changes.push(EnvChange::Removed(key)),
+                Some(&new_value) if new_value != old_value => {
+                    changes.push(EnvChange::Changed(key, old_value, new_value))
+                }
+                _ => {}
+            }
+        }
+        for (&key, &new_value) in new.iter() {
+            if !old.contains_key(key) {
+                changes.push(EnvChange::Added(key, new_value));
+            }
+        }
+        changes
+    }
+
     pub fn as_ptr_vec(&self) -> Vec<*const core::ffi::c_char> {
         let mut envp = Vec::new();
         for s in self.env.iter() {
@@ -90,3 +135,20 @@ impl Envp {
         envp
     }
 }
+
+/// values may well be secrets, only the names of the variables are displayed
+pub enum EnvChange<'a> {
+    Added(&'a BStr, &'a BStr),
+    Removed(&'a BStr),
+    Changed(&'a BStr, &'a BStr, &'a BStr),
+}
+
+impl core::fmt::Display for EnvChange<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            EnvChange::Added(key, _) => write!(f, "{} added", key),
+            EnvChange::Removed(key) => write!(f, "{} removed", key),
+            EnvChange::Changed(key, _, _) => write!(f, "{} changed", key),
+        }
+    }
+}
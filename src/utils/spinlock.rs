@@ -0,0 +1,34 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// minimal `lock_api` backend, we can't rely on libc's pthread mutexes here
+pub struct RawSpinlock {
+    locked: AtomicBool,
+}
+
+unsafe impl lock_api::RawMutex for RawSpinlock {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: RawSpinlock = RawSpinlock {
+        locked: AtomicBool::new(false),
+    };
+
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock(&self) {
+        while !self.try_lock() {
+            // critical sections are short, but the holder might have been preempted
+            rustix::process::sched_yield();
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    unsafe fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+pub type Mutex<T> = lock_api::Mutex<RawSpinlock, T>;